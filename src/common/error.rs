@@ -19,6 +19,26 @@ pub enum DownloadError {
     RequestError(reqwest::Error),
     IOError(io::Error),
     JsonParseError(serde_json::error::Error),
+    /// the server ignored or mis-answered a range request, the caller may fall back to a single stream
+    UnexpectedRange(Error),
+    /// fewer or more bytes arrived than the segment or file should hold
+    SizeMismatch(Error),
+    /// another process is downloading the same url or into the same file
+    Locked(Error),
+    /// the downloaded file doesn't match the expected digest
+    ChecksumMismatch(Error),
+    /// the cache or target filesystem can't hold what is left to download
+    InsufficientSpace(Error),
+    /// the target file exists and the existing file policy forbids touching it
+    AlreadyExists(Error),
+    /// the remote file is not a zip archive pget can read
+    InvalidArchive(Error),
+    /// a response delivered no bytes for too long or fell below the throughput floor
+    Stalled(Error),
+    /// the server answered `429` or `503`, the connections pause for its `Retry-After`
+    RateLimited(Error),
+    /// the url now serves a different version of the file than the download started with
+    RemoteChanged(Error),
 }
 
 impl DownloadError {
@@ -34,6 +54,66 @@ impl DownloadError {
             code: ErrorCode::RetryableError,
         })
     }
+    pub fn unexpected_range(message: &str) -> DownloadError {
+        DownloadError::UnexpectedRange(Error {
+            error_message: message.to_string(),
+            code: ErrorCode::RetryableError,
+        })
+    }
+    pub fn size_mismatch(message: &str) -> DownloadError {
+        DownloadError::SizeMismatch(Error {
+            error_message: message.to_string(),
+            code: ErrorCode::RetryableError,
+        })
+    }
+    pub fn locked(message: &str) -> DownloadError {
+        DownloadError::Locked(Error {
+            error_message: message.to_string(),
+            code: ErrorCode::RetryableError,
+        })
+    }
+    pub fn checksum_mismatch(message: &str) -> DownloadError {
+        DownloadError::ChecksumMismatch(Error {
+            error_message: message.to_string(),
+            code: ErrorCode::RetryableError,
+        })
+    }
+    pub fn insufficient_space(message: &str) -> DownloadError {
+        DownloadError::InsufficientSpace(Error {
+            error_message: message.to_string(),
+            code: ErrorCode::RetryableError,
+        })
+    }
+    pub fn already_exists(message: &str) -> DownloadError {
+        DownloadError::AlreadyExists(Error {
+            error_message: message.to_string(),
+            code: ErrorCode::RetryableError,
+        })
+    }
+    pub fn invalid_archive(message: &str) -> DownloadError {
+        DownloadError::InvalidArchive(Error {
+            error_message: message.to_string(),
+            code: ErrorCode::RetryableError,
+        })
+    }
+    pub fn stalled(message: &str) -> DownloadError {
+        DownloadError::Stalled(Error {
+            error_message: message.to_string(),
+            code: ErrorCode::RetryableError,
+        })
+    }
+    pub fn rate_limited(message: &str) -> DownloadError {
+        DownloadError::RateLimited(Error {
+            error_message: message.to_string(),
+            code: ErrorCode::RetryableError,
+        })
+    }
+    pub fn remote_changed(message: &str) -> DownloadError {
+        DownloadError::RemoteChanged(Error {
+            error_message: message.to_string(),
+            code: ErrorCode::RetryableError,
+        })
+    }
 }
 
 impl From<reqwest::Error> for DownloadError {
@@ -52,3 +132,12 @@ impl From<serde_json::error::Error> for DownloadError {
         DownloadError::JsonParseError(value)
     }
 }
+
+impl From<DownloadError> for io::Error {
+    fn from(value: DownloadError) -> Self {
+        match value {
+            DownloadError::IOError(e) => e,
+            other => io::Error::other(format!("{:?}", other)),
+        }
+    }
+}
@@ -0,0 +1,260 @@
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::sync::broadcast;
+
+use crate::download::events::DownloadEvent;
+
+/// how many times a segment is re-requested from its current offset before giving up
+pub const DEFAULT_RETRIES: usize = 5;
+/// files up to this size are fetched with one plain request, without segments or a cache dir
+pub const DEFAULT_SMALL_FILE_THRESHOLD: usize = 1024 * 1024;
+
+/// Tuning knobs for [`crate::download_with_options`], the defaults behave like [`crate::download`].
+#[derive(Clone, Debug)]
+pub struct DownloadOptions {
+    /// keep the `.cache` dir after the file is assembled
+    pub keep_cache: bool,
+    /// how many times a segment is resumed after a broken or short response
+    pub retries: usize,
+    /// files up to this many bytes are fetched with a single request
+    pub small_file_threshold: usize,
+    /// names the resume state instead of the url, for urls whose query changes on every
+    /// request (signed CDN links) but which still point at the same file
+    pub cache_key: Option<String>,
+    /// where the resume state is kept
+    pub cache_location: CacheLocation,
+    /// what to do when another process is downloading the same url or into the same file
+    pub lock_policy: LockPolicy,
+    /// hex sha256 the finished file must have before it is moved to the target path
+    pub expected_sha256: Option<String>,
+    /// compare the worst-case space needed with the free space before downloading
+    pub check_disk_space: bool,
+    /// reserve the full size of cache and output files up front (`fallocate` on Linux)
+    pub preallocate: bool,
+    /// what to do when the target file exists already
+    pub existing_file: ExistingFilePolicy,
+    /// when a segment's response counts as stalled and is reconnected from its current offset,
+    /// each reconnect uses up one of the `retries`. on by default, `None` waits for bytes
+    /// forever like earlier versions did
+    pub stall: Option<StallPolicy>,
+    /// publishes [`DownloadEvent`]s as the download goes on. a receiver that falls behind
+    /// misses events (`RecvError::Lagged`) instead of slowing the download down
+    pub events: Option<broadcast::Sender<DownloadEvent>>,
+    /// asked for a fresh url when a segment is refused with `401` or `403`. without one the
+    /// original url is probed again, which follows its redirects anew. the file behind the new
+    /// url must have the same size and ETag, so the cached segments stay valid
+    pub refresh_url: Option<UrlRefresh>,
+    /// pick the number of open connections by the measured throughput instead of opening
+    /// `threads` of them, see [`AdaptiveConnections`]
+    pub adaptive_connections: Option<AdaptiveConnections>,
+}
+
+/// Connection count that follows the throughput. The download is split into `max` segments,
+/// it starts with `min` connections and adds more while the aggregate throughput keeps
+/// improving. Errors and `429`/`503` take it back down, never below `min`. The `threads`
+/// argument of the download is ignored then.
+#[derive(Clone, Debug)]
+pub struct AdaptiveConnections {
+    pub min: usize,
+    pub max: usize,
+    /// how long the throughput is measured before the count changes
+    pub interval: Duration,
+}
+
+impl Default for AdaptiveConnections {
+    fn default() -> AdaptiveConnections {
+        AdaptiveConnections {
+            min: 2,
+            max: 16,
+            interval: Duration::from_secs(2),
+        }
+    }
+}
+
+/// Hands out a fresh url for the same file once the current one is refused, e.g. a presigned
+/// link that expired halfway through a download. It gets the refused url, `None` gives up.
+#[derive(Clone)]
+pub struct UrlRefresh(Arc<RefreshFn>);
+
+type RefreshFn = dyn Fn(&str) -> Option<String> + Send + Sync;
+
+impl UrlRefresh {
+    pub fn new<F>(refresh: F) -> UrlRefresh
+    where
+        F: Fn(&str) -> Option<String> + Send + Sync + 'static,
+    {
+        UrlRefresh(Arc::new(refresh))
+    }
+
+    pub(crate) fn call(&self, refused_url: &str) -> Option<String> {
+        (self.0)(refused_url)
+    }
+}
+
+impl fmt::Debug for UrlRefresh {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("UrlRefresh(..)")
+    }
+}
+
+/// Behaviour when the target file or the cache dir is locked by another download.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LockPolicy {
+    /// block until the other download is done, then download as usual
+    #[default]
+    Wait,
+    /// return [`crate::common::error::DownloadError::Locked`] right away
+    FailFast,
+    /// block until the other download is done and return once the target file exists
+    Observe,
+}
+
+/// Root dir for the resume state, every download gets its own `<root>/<hash>` dir below it.
+#[derive(Clone, Debug, Default)]
+pub enum CacheLocation {
+    /// `$PGET_CACHE_DIR` when it is set, otherwise the same as [`CacheLocation::BesideTarget`]
+    #[default]
+    Default,
+    /// a `.cache` dir next to the target file, the layout of earlier versions
+    BesideTarget,
+    /// `$XDG_CACHE_HOME/pget`, or `$HOME/.cache/pget` when `XDG_CACHE_HOME` is not set
+    Xdg,
+    /// an explicit root dir
+    Dir(PathBuf),
+}
+
+impl Default for DownloadOptions {
+    fn default() -> DownloadOptions {
+        DownloadOptions {
+            keep_cache: true,
+            retries: DEFAULT_RETRIES,
+            small_file_threshold: DEFAULT_SMALL_FILE_THRESHOLD,
+            cache_key: None,
+            cache_location: CacheLocation::Default,
+            lock_policy: LockPolicy::Wait,
+            expected_sha256: None,
+            check_disk_space: true,
+            preallocate: false,
+            existing_file: ExistingFilePolicy::Overwrite,
+            stall: Some(StallPolicy::default()),
+            events: None,
+            refresh_url: None,
+            adaptive_connections: None,
+        }
+    }
+}
+
+/// Behaviour when the target file exists before the download starts.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ExistingFilePolicy {
+    /// replace it once the new file is complete
+    #[default]
+    Overwrite,
+    /// keep it without downloading when its size matches the remote file and so does
+    /// [`DownloadOptions::expected_sha256`], or without one the ETag it was downloaded with.
+    /// only downloads with this policy record the ETag (as `.<file>.etag`), otherwise replace it
+    SkipIfMatch,
+    /// keep it and write the new file as `<stem>.<n>.<ext>` with the first free `n`
+    Rename,
+    /// return [`crate::common::error::DownloadError::AlreadyExists`]
+    Fail,
+    /// treat its bytes as an already downloaded prefix of the remote file and fetch only the
+    /// rest, e.g. for files left half-finished by curl or wget
+    AdoptPrefix,
+}
+
+/// Tuning knobs for [`crate::RemoteFile`] and [`crate::AsyncRemoteFile`].
+#[derive(Clone, Debug)]
+pub struct RemoteFileOptions {
+    /// bytes fetched by one range request
+    pub block_size: u64,
+    /// how many blocks are kept in memory
+    pub cache_blocks: usize,
+    /// blocks fetched ahead of a sequential read
+    pub read_ahead: usize,
+    /// how many times a block request is repeated before the read fails
+    pub retries: usize,
+    /// see [`DownloadOptions::stall`]
+    pub stall: Option<StallPolicy>,
+    /// see [`DownloadOptions::refresh_url`]
+    pub refresh_url: Option<UrlRefresh>,
+    /// also write fetched blocks into the resume state of a later full download
+    pub spill: Option<SpillOptions>,
+}
+
+impl Default for RemoteFileOptions {
+    fn default() -> RemoteFileOptions {
+        RemoteFileOptions {
+            block_size: 1024 * 1024,
+            cache_blocks: 64,
+            read_ahead: 4,
+            retries: DEFAULT_RETRIES,
+            stall: Some(StallPolicy::default()),
+            refresh_url: None,
+            spill: None,
+        }
+    }
+}
+
+/// The full download that should reuse blocks read through a remote file. Blocks only count
+/// when they continue the cached prefix of a segment, and `threads` has to match the download.
+/// The remote file holds the cache lock while it is open, spilling is skipped if a download
+/// holds it already.
+#[derive(Clone, Debug)]
+pub struct SpillOptions {
+    pub output_file: PathBuf,
+    pub threads: usize,
+    /// cache location and key of the download
+    pub download_options: DownloadOptions,
+}
+
+/// Tuning knobs for the streaming downloads, [`crate::download_to_writer`] and friends.
+/// At most `buffer_chunks * chunk_size` bytes are held in memory.
+#[derive(Clone, Debug)]
+pub struct StreamOptions {
+    /// bytes fetched by one range request
+    pub chunk_size: u64,
+    /// chunks fetched or waiting for their turn at once, a slow reader stops new fetches
+    pub buffer_chunks: usize,
+    /// how many times a chunk is resumed after a broken, short or stalled response
+    pub retries: usize,
+    /// see [`DownloadOptions::stall`]
+    pub stall: Option<StallPolicy>,
+    /// see [`DownloadOptions::refresh_url`]
+    pub refresh_url: Option<UrlRefresh>,
+}
+
+impl Default for StreamOptions {
+    fn default() -> StreamOptions {
+        StreamOptions {
+            chunk_size: 4 * 1024 * 1024,
+            buffer_chunks: 16,
+            retries: DEFAULT_RETRIES,
+            stall: Some(StallPolicy::default()),
+            refresh_url: None,
+        }
+    }
+}
+
+/// When an open response counts as stalled.
+#[derive(Clone, Debug)]
+pub struct StallPolicy {
+    /// no bytes arrived for this long
+    pub timeout: Duration,
+    /// fewer bytes per second than this arrived, averaged over `window`
+    pub min_throughput: Option<u64>,
+    pub window: Duration,
+}
+
+impl Default for StallPolicy {
+    fn default() -> StallPolicy {
+        StallPolicy {
+            timeout: Duration::from_secs(30),
+            min_throughput: None,
+            window: Duration::from_secs(10),
+        }
+    }
+}
@@ -1,36 +1,70 @@
 use std::collections::HashMap;
+use std::ffi::OsString;
 use std::fs::{self, File, OpenOptions};
-use std::hash::{DefaultHasher, Hash, Hasher};
-use std::io::{self, SeekFrom};
-use std::io::{prelude::*, BufWriter};
-use std::path::{self, Path, PathBuf};
+use std::future::Future;
+use std::io;
+use std::io::{prelude::*, BufReader};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use futures::future::join_all;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use tokio::runtime::Runtime;
-use tokio::task::JoinHandle;
+use tokio::task::JoinError;
+use tokio::time::MissedTickBehavior;
+use tracing::{debug, info, instrument, warn, Instrument};
 
 use crate::common;
 use crate::common::error::DownloadError;
+use crate::common::options::{
+    CacheLocation, DownloadOptions, ExistingFilePolicy, LockPolicy, StallPolicy,
+};
 
-use self::network::Network;
+use self::events::{DownloadEvent, Events};
+use self::lock::PathLock;
+use self::network::{Network, RemoteInfo};
+use self::refresh::SegmentUrl;
+use self::report::{DownloadReport, FetchCounter, TransferStats};
+use self::retry::Retries;
+use self::sink::{DigestSink, FileSink, MemorySink, StorageSink};
+use self::watchdog::Watchdog;
+use self::writer::{SegmentWriter, Writer};
 
-mod network;
+mod disk;
+pub(crate) mod events;
+mod finalize;
+mod lock;
+pub(crate) mod network;
+pub(crate) mod plan;
 #[cfg(feature = "progress_bar")]
 mod progress;
+mod refresh;
+pub(crate) mod remote_file;
+pub(crate) mod report;
+mod retry;
+pub(crate) mod sink;
+pub(crate) mod stream;
+mod throttle;
+mod watchdog;
+mod writer;
+
+#[cfg(test)]
+mod test_server;
+pub(crate) mod zip;
 
 #[cfg(not(feature = "progress_bar"))]
 pub(crate) mod mock_progress;
 #[cfg(not(feature = "progress_bar"))]
 use self::mock_progress as progress;
 
-use progress::Progress;
-
 static CACHE_STATUS_FILE: &str = "download_status.json";
+static CACHE_REMOTE_FILE: &str = "remote.json";
+/// how often the segment positions are recorded for a later resume
+const CHECKPOINT_INTERVAL: Duration = Duration::from_secs(1);
 static CACHE_PREFIX_PATH: &str = ".cache";
+static CACHE_DIR_ENV: &str = "PGET_CACHE_DIR";
 
 pub(crate) struct Download {
     pub url: String,
@@ -39,32 +73,68 @@ pub(crate) struct Download {
     pub network: network::Network,
     pub progress: progress::Progress,
     pub keep_cache: bool,
+    pub options: DownloadOptions,
+    /// only fetch these bytes of the remote file, the output file holds just them
+    pub range: Option<Range<u64>>,
+    /// what the probe learned, checked against the cache's `remote.json` before resuming
+    pub remote_info: Option<RemoteInfo>,
+    /// numbers for the [`DownloadReport`], shared with the segment tasks
+    pub stats: Arc<TransferStats>,
 }
 
-fn copy_n_byte<R: ?Sized, W: ?Sized>(reader: &mut R, writer: &mut W, len: usize) -> io::Result<u64>
+type SegmentResult = common::error::Result<(PathBuf, usize, usize)>;
+
+/// split `[start, content_length)` into up to `threads` adjacent `[range_start, range_end)` bounds
+fn segment_bounds(threads: usize, start: usize, content_length: usize) -> Vec<(usize, usize)> {
+    let length = content_length - start;
+    // every segment but the last needs at least one byte, see `chunk_size`
+    let threads = threads.clamp(1, (length / 2).max(1));
+    let chunk_size = length / threads - 1;
+    let mut range_start = start;
+    let mut bounds = Vec::with_capacity(threads);
+    for thread in 0..threads {
+        let mut range_end = chunk_size + range_start;
+        if thread == (threads - 1) {
+            range_end = content_length
+        }
+        bounds.push((range_start, range_end));
+        range_start += chunk_size;
+    }
+    bounds
+}
+
+/// copy `len` bytes of `reader` into `sink` from `offset` on
+fn copy_n_byte<R>(
+    reader: &mut R,
+    sink: &mut dyn StorageSink,
+    offset: u64,
+    len: usize,
+) -> io::Result<u64>
 where
-    R: Read,
-    W: Write,
+    R: Read + ?Sized,
 {
-    let bf_len = 1024;
+    let mut buffer = vec![0u8; 64 * 1024];
     let mut count = 0;
-    if len > bf_len {
-        let mut buffer = vec![0u8; 1024];
-        while count + bf_len <= len {
-            reader.read_exact(&mut buffer)?;
-            count += bf_len;
-            writer.write(&buffer)?;
-        }
-    }
-    let rest = len % bf_len;
-    let mut rest_buf = vec![0u8; rest];
-    reader.read_exact(&mut rest_buf)?;
-    writer.write(&rest_buf)?;
-    writer.flush()?;
-    count += rest;
+    while count < len {
+        let chunk = buffer.len().min(len - count);
+        reader.read_exact(&mut buffer[..chunk])?;
+        sink.write_at(offset + count as u64, &buffer[..chunk])?;
+        count += chunk;
+    }
+    sink.flush()?;
     Ok(count as u64)
 }
 
+/// one byte range `[range_start, range_end)` of the target file, served by its own task
+#[derive(Clone)]
+struct Segment {
+    thread_number: usize,
+    range_start: usize,
+    range_end: usize,
+    /// offset to request from, `None` when the cache already holds the whole segment
+    query_start: Option<usize>,
+}
+
 #[derive(Deserialize, Serialize)]
 struct DownloadProcess {
     pub thread: usize,
@@ -81,6 +151,10 @@ impl Default for Download {
             network: network::Network::default(),
             progress: progress::Progress::default(),
             keep_cache: false,
+            options: DownloadOptions::default(),
+            range: None,
+            remote_info: None,
+            stats: Arc::new(TransferStats::default()),
         }
     }
 }
@@ -88,168 +162,1133 @@ impl Default for Download {
 fn load_json<P: AsRef<Path>>(file_path: P) -> Option<Vec<DownloadProcess>> {
     let file_content = std::fs::read_to_string(file_path).ok()?;
     let processes: Vec<DownloadProcess> = serde_json::from_str(&file_content).ok()?;
-    return Some(processes);
+    Some(processes)
 }
+/// sha256 is stable across toolchains, unlike `DefaultHasher`, so cache dirs survive upgrades
 fn hash_string_to_hex(input: &str) -> String {
-    let mut hasher = DefaultHasher::new();
-    input.hash(&mut hasher);
-    let hash_result = hasher.finish();
-    format!("{:x}", hash_result)
+    hex::encode(Sha256::digest(input.as_bytes()))
+}
+
+/// scheme and host are lowercased, default ports, the fragment and the parameters of a
+/// presigned url are dropped, so a fresh link to the same object resumes the same cache
+fn normalize_url(url: &str) -> String {
+    match reqwest::Url::parse(url) {
+        Ok(mut parsed) => {
+            parsed.set_fragment(None);
+            let names = parsed
+                .query_pairs()
+                .map(|(name, _)| name.to_ascii_lowercase())
+                .collect::<Vec<_>>();
+            let presigned = Presigned::detect(&names);
+            if names.iter().any(|name| presigned.signs(name)) {
+                let kept = parsed
+                    .query_pairs()
+                    .filter(|(name, _)| !presigned.signs(&name.to_ascii_lowercase()))
+                    .map(|(name, value)| (name.into_owned(), value.into_owned()))
+                    .collect::<Vec<_>>();
+                if kept.is_empty() {
+                    parsed.set_query(None);
+                } else {
+                    parsed.query_pairs_mut().clear().extend_pairs(kept);
+                }
+            }
+            parsed.to_string()
+        }
+        Err(_) => url.to_string(),
+    }
+}
+
+/// which kinds of presigned url a query is, told by their signature parameters. plain urls
+/// keep parameters like `expires` or `signature`, they may pick what the server sends
+#[derive(Default)]
+struct Presigned {
+    /// `X-Amz-Signature`, S3 signature version 4
+    amz: bool,
+    /// `X-Goog-Signature`, GCS signature version 4
+    goog: bool,
+    /// `Signature` with `Key-Pair-Id` (CloudFront) or `AWSAccessKeyId` (S3 version 2)
+    legacy: bool,
+}
+
+impl Presigned {
+    /// `names` are the lowercased query parameter names
+    fn detect(names: &[String]) -> Presigned {
+        let has = |wanted: &str| names.iter().any(|name| name == wanted);
+        Presigned {
+            amz: has("x-amz-signature"),
+            goog: has("x-goog-signature"),
+            legacy: has("signature") && (has("key-pair-id") || has("awsaccesskeyid")),
+        }
+    }
+
+    /// whether the lowercased parameter `name` signs or expires the url
+    fn signs(&self, name: &str) -> bool {
+        (self.amz && name.starts_with("x-amz-"))
+            || (self.goog && name.starts_with("x-goog-"))
+            || (self.legacy
+                && matches!(
+                    name,
+                    "awsaccesskeyid" | "signature" | "expires" | "key-pair-id" | "policy"
+                ))
+    }
+}
+
+/// `[start, end)` of the remote file that ends up in the output, `range` or the whole file.
+/// `end` is `None` for a whole file of unknown length, a range needs the length to be checked
+fn output_bounds(
+    range: Option<&Range<u64>>,
+    content_length: Option<u64>,
+) -> common::error::Result<(u64, Option<u64>)> {
+    let Some(range) = range else {
+        return Ok((0, content_length));
+    };
+    let content_length = network::ranged_length(content_length)?;
+    if range.start >= range.end || range.end > content_length {
+        return Err(DownloadError::parameter(&format!(
+            "range {}..{} is empty or outside of the remote file (0..{})",
+            range.start, range.end, content_length
+        )));
+    }
+    Ok((range.start, Some(range.end)))
+}
+
+/// the root dir of the resume state of `filename`, `env` looks up an environment variable
+fn resolve_cache_root(
+    cache_location: &CacheLocation,
+    filename: &Path,
+    env: impl Fn(&str) -> Option<OsString>,
+) -> common::error::Result<PathBuf> {
+    let beside_target = || -> common::error::Result<PathBuf> {
+        let file_dir = filename.parent().ok_or(DownloadError::parameter(
+            "target file should have a parent dir",
+        ))?;
+        Ok(file_dir.join(CACHE_PREFIX_PATH))
+    };
+    match cache_location {
+        CacheLocation::Default => match env(CACHE_DIR_ENV) {
+            Some(dir) if !dir.is_empty() => Ok(PathBuf::from(dir)),
+            _ => beside_target(),
+        },
+        CacheLocation::BesideTarget => beside_target(),
+        CacheLocation::Xdg => {
+            let xdg_home = env("XDG_CACHE_HOME")
+                .map(PathBuf::from)
+                .filter(|dir| dir.is_absolute());
+            let home = env("HOME")
+                .or_else(|| env("USERPROFILE"))
+                .map(|home| PathBuf::from(home).join(CACHE_PREFIX_PATH));
+            xdg_home
+                .or(home)
+                .map(|dir| dir.join("pget"))
+                .ok_or(DownloadError::parameter(
+                    "neither XDG_CACHE_HOME nor HOME is set",
+                ))
+        }
+        CacheLocation::Dir(dir) => Ok(dir.clone()),
+    }
+}
+
+/// `<stem>.<n>.<ext>` with the first `n` for which neither the file nor its part file exists
+fn free_file_name(file_path: &Path) -> PathBuf {
+    let stem = file_path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let extension = file_path
+        .extension()
+        .map(|extension| format!(".{}", extension.to_string_lossy()))
+        .unwrap_or_default();
+    let mut n = 1;
+    loop {
+        let candidate = file_path.with_file_name(format!("{}.{}{}", stem, n, extension));
+        let taken =
+            candidate.exists() || finalize::part_path(&candidate).is_ok_and(|part| part.exists());
+        if !taken {
+            return candidate;
+        }
+        n += 1;
+    }
 }
 
 impl Download {
-    pub fn get(self) -> common::error::Result<()> {
+    #[instrument(
+        skip_all,
+        fields(url = %self.url, file = %self.filename.display(), threads = self.threads)
+    )]
+    pub fn get(mut self) -> common::error::Result<DownloadReport> {
+        let stats = self.stats.clone();
+        let url = self.url.clone();
+        let (target_lock, waited) = self.lock(&self.target_lock_path()?)?;
+        if waited && self.options.lock_policy == LockPolicy::Observe && self.filename.exists() {
+            // the download we waited for produced the file already
+            let total_bytes = fs::metadata(&self.filename)?.len();
+            stats.set_adopted(total_bytes);
+            return Ok(stats.report(&url, self.filename, total_bytes));
+        }
+        let mut renamed_lock = None;
+        if self.filename.exists() {
+            match self.options.existing_file {
+                ExistingFilePolicy::Fail => {
+                    return Err(DownloadError::already_exists(&format!(
+                        "{} exists already",
+                        self.filename.display()
+                    )))
+                }
+                ExistingFilePolicy::Rename => {
+                    self.filename = free_file_name(&self.filename);
+                    renamed_lock = Some(self.lock(&self.target_lock_path()?)?.0);
+                }
+                _ => (),
+            }
+        }
+        let cache_dir = self.cache_dir().ok();
+        let filename = self.filename.clone();
+        let version_path = self.version_path()?;
+        let range = self.range.clone();
+        let skip_if_match = self.options.existing_file == ExistingFilePolicy::SkipIfMatch;
+        let result = self.fetch();
+        drop(renamed_lock);
+        drop(target_lock);
+        if let Some(cache_dir) = cache_dir {
+            Download::remove_empty_cache_root(&cache_dir);
+        }
+        if let Err(e) = &result {
+            warn!(error = ?e, "download failed");
+        }
+        result?;
+        let total_bytes = fs::metadata(&filename)?.len();
+        let report = stats.report(&url, filename, total_bytes);
+        // a later download with `SkipIfMatch` keeps the file while the ETag stays the same. only
+        // that policy records it, any other forgets what an earlier download recorded
+        finalize::record_version(
+            &version_path,
+            report
+                .etag
+                .as_deref()
+                .filter(|_| skip_if_match)
+                .map(|etag| finalize::version_key(etag, range.as_ref())),
+        )?;
+        info!(
+            total_bytes,
+            resumed_bytes = report.resumed_bytes,
+            fetched_bytes = report.fetched_bytes,
+            elapsed = ?report.elapsed,
+            "download finished"
+        );
+        Ok(report)
+    }
+
+    fn fetch(mut self) -> common::error::Result<()> {
         let rt = tokio::runtime::Builder::new_multi_thread()
-            .worker_threads(self.threads)
+            .worker_threads(self.worker_threads())
             .thread_name("pget")
             .enable_all()
             .build()?;
-        let content_length_resp: Option<u64> =
-            rt.block_on(self.network.get_content_length(&self.url))?;
+        let remote_info = rt.block_on(self.network.probe(&self.url))?;
+        let content_length_resp = remote_info.range_length();
+        self.stats.set_remote(&remote_info);
+        let events = Events::new(self.options.events.clone());
+        // the final url comes out of the http client in its normal form
+        if normalize_url(&remote_info.final_url) != normalize_url(&self.url) {
+            events.emit(DownloadEvent::Redirected {
+                from: self.url.clone(),
+                to: remote_info.final_url.clone(),
+            });
+        }
+        events.emit(DownloadEvent::Probed {
+            remote: remote_info.clone(),
+        });
+        self.remote_info = Some(remote_info);
+        if let Some(range) = self.range.clone() {
+            return self.fetch_range(&rt, content_length_resp, range);
+        }
+
+        // bytes of the existing target file that are kept as the start of the download
+        let mut prefix_length = 0;
+        if let (Some(content_length), Ok(metadata)) =
+            (content_length_resp, fs::metadata(&self.filename))
+        {
+            match self.options.existing_file {
+                ExistingFilePolicy::SkipIfMatch
+                    if metadata.len() == content_length
+                        && self.matches_existing(&self.filename)? =>
+                {
+                    self.stats.set_adopted(content_length);
+                    return Ok(());
+                }
+                ExistingFilePolicy::AdoptPrefix if metadata.len() <= content_length => {
+                    prefix_length = metadata.len();
+                }
+                _ => (),
+            }
+        }
 
+        let network = self.network.clone();
+        let url = self.url.clone();
+        let target_filename = self.filename.clone();
+        let part_filename = finalize::part_path(&target_filename)?;
+        let expected_sha256 = self.options.expected_sha256.clone();
+        let stats = self.stats.clone();
+        let finalize = |expected_length: Option<u64>, sha256: Option<String>| {
+            let sha256 = finalize::finalize_with_sha256(
+                &part_filename,
+                &target_filename,
+                expected_length,
+                expected_sha256.as_deref(),
+                sha256,
+            )?;
+            events.emit(DownloadEvent::Verified {
+                path: target_filename.clone(),
+                sha256: sha256.clone(),
+            });
+            stats.set_sha256(sha256);
+            common::error::Result::Ok(())
+        };
         match content_length_resp {
+            Some(0) => {
+                File::create(&part_filename)?.sync_all()?;
+                finalize(Some(0), Some(hex::encode(Sha256::digest(b""))))?;
+            }
+            Some(content_length) if prefix_length == content_length => {
+                // the adopted file is complete already, it only has to pass the checks
+                if !self.matches_expected_sha256(&target_filename)? {
+                    return Err(DownloadError::checksum_mismatch(&format!(
+                        "{} doesn't match the expected sha256",
+                        target_filename.display()
+                    )));
+                }
+                self.stats.set_adopted(prefix_length);
+            }
+            Some(content_length)
+                if content_length as usize <= self.options.small_file_threshold =>
+            {
+                if self.options.check_disk_space {
+                    disk::check_space(&[(self.target_dir()?, content_length)])?;
+                }
+                let (_, sha256) = rt.block_on(Download::single_stream_to_file(
+                    &network,
+                    &url,
+                    &stats,
+                    &part_filename,
+                    Some(content_length),
+                ))?;
+                finalize(Some(content_length), sha256)?;
+            }
             Some(content_length) => {
-                let cache_dir = self.generate_cache_dir()?;
-                let target_filename = self.filename.clone();
-                let keep_cache = self.keep_cache;
-                let children = Download::spawn_threads(self, &rt,content_length as usize)?;
-                let request_result = rt.block_on(join_all(children)).into_iter().filter_map(|x|x.ok()).filter_map(|x|x.ok()).collect::<Vec<_>>();
-                Download::assemble(cache_dir,target_filename.clone(), request_result, keep_cache)?;
-                let target_file_handle = OpenOptions::new()
-                    .write(true)
-                    .create(true)
-                    .open(target_filename)?;
-                target_file_handle.set_len(content_length)?;
+                match Download::fetch_segments(
+                    self,
+                    &rt,
+                    prefix_length as usize,
+                    content_length as usize,
+                ) {
+                    Err(DownloadError::UnexpectedRange(e)) => {
+                        // the server does not honour ranges, fetch the file in one go
+                        warn!(error = ?e, "ranges are not honoured, falling back to one request");
+                        let (_, sha256) = rt.block_on(Download::single_stream_to_file(
+                            &network,
+                            &url,
+                            &stats,
+                            &part_filename,
+                            Some(content_length),
+                        ))?;
+                        finalize(Some(content_length), sha256)?;
+                    }
+                    result => result?,
+                }
+            }
+            None => {
+                info!("the server doesn't report the length or ranges, fetching with one request");
+                let (received, sha256) = rt.block_on(Download::single_stream_to_file(
+                    &network,
+                    &url,
+                    &stats,
+                    &part_filename,
+                    None,
+                ))?;
+                finalize(Some(received), sha256)?;
             }
-            None => println!("Content length is not present for this URL. Support for this type of hosted file will be added in the future."),
         }
         Ok(())
     }
 
-    pub(crate) fn generate_cache_dir(&self) -> common::error::Result<PathBuf> {
-        let file_path = self.filename.clone();
-        let file_dir = file_path.parent().ok_or(DownloadError::parameter(
-            "target file should have a parent dir",
-        ))?;
-        let hash_name = hash_string_to_hex(&self.url);
-        let cache_dir = file_dir.join(CACHE_PREFIX_PATH).join(hash_name);
+    /// download `self.range` into the target file
+    fn fetch_range(
+        self,
+        rt: &Runtime,
+        content_length_resp: Option<u64>,
+        range: Range<u64>,
+    ) -> common::error::Result<()> {
+        output_bounds(Some(&range), content_length_resp)?;
+        let length = range.end - range.start;
+
+        let mut prefix_length = 0;
+        if let Ok(metadata) = fs::metadata(&self.filename) {
+            match self.options.existing_file {
+                ExistingFilePolicy::SkipIfMatch
+                    if metadata.len() == length && self.matches_existing(&self.filename)? =>
+                {
+                    self.stats.set_adopted(length);
+                    return Ok(());
+                }
+                ExistingFilePolicy::AdoptPrefix if metadata.len() < length => {
+                    prefix_length = metadata.len();
+                }
+                _ => (),
+            }
+        }
+
+        if length as usize > self.options.small_file_threshold {
+            return Download::fetch_segments(
+                self,
+                rt,
+                (range.start + prefix_length) as usize,
+                range.end as usize,
+            );
+        }
+        // small ranges go straight into the part file with a single validated request
+        if self.options.check_disk_space {
+            disk::check_space(&[(self.target_dir()?, length)])?;
+        }
+        let part_filename = finalize::part_path(&self.filename)?;
+        let mut part_sink = FileSink::create(&part_filename)?;
+        let mut sink = DigestSink::new(&mut part_sink);
+        self.stats.add_segment(1, range.start, range.end, 0);
+        let writer = Writer::new();
+        let request = rt.spawn(Download::request(
+            writer.segment(0, range.start, 1),
+            Arc::new(self.network),
+            self.stats.clone(),
+            Events::new(self.options.events.clone()),
+            1,
+            Arc::new(SegmentUrl::new(
+                self.url,
+                self.options.refresh_url.clone(),
+                self.remote_info.clone(),
+                Events::new(self.options.events.clone()),
+            )),
+            range.start as usize,
+            range.end as usize,
+            self.options.retries,
+            self.options.stall.clone(),
+        ));
+        let (written, mut results) =
+            writer.drive(rt, vec![request], &mut [&mut sink], &self.progress);
+        written?;
+        results
+            .pop()
+            .expect("one request was joined")
+            .map_err(|e| DownloadError::system(&e.to_string()))??;
+        let sha256 = sink.sha256();
+        part_sink.sync()?;
+        drop(part_sink);
+        let sha256 = finalize::finalize_with_sha256(
+            &part_filename,
+            &self.filename,
+            Some(length),
+            self.options.expected_sha256.as_deref(),
+            sha256,
+        )?;
+        Events::new(self.options.events).emit(DownloadEvent::Verified {
+            path: self.filename,
+            sha256: sha256.clone(),
+        });
+        self.stats.set_sha256(sha256);
+        Ok(())
+    }
+
+    /// download `[start, end)` through cached segments and finalize the target file.
+    /// The target file starts at `self.range.start` (0 without a range), bytes before `start`
+    /// are taken from the existing target file.
+    fn fetch_segments(self, rt: &Runtime, start: usize, end: usize) -> common::error::Result<()> {
+        let output_offset = self.range.as_ref().map_or(0, |range| range.start as usize);
+        let target_filename = self.filename.clone();
+        let part_filename = finalize::part_path(&target_filename)?;
+        let expected_sha256 = self.options.expected_sha256.clone();
+        let keep_cache = self.keep_cache;
+        let preallocate = self.options.preallocate;
+        let stats = self.stats.clone();
+        let events = Events::new(self.options.events.clone());
+
+        let cache_dir = self.generate_cache_dir(start)?;
+        let (_cache_lock, _) = self.lock(&Download::cache_lock_path(&cache_dir))?;
+        if let Some(remote_info) = &self.remote_info {
+            Download::check_remote_info(&cache_dir, remote_info)?;
+        }
+        let tuner = self.tune_connections().map(|tuner| rt.spawn(tuner));
+        let request_result = Download::run_segments(self, rt, cache_dir.clone(), start, end);
+        if let Some(tuner) = tuner {
+            tuner.abort();
+        }
+        let segments = match Download::collect_segments(request_result?) {
+            Err(e @ DownloadError::UnexpectedRange(_)) => {
+                // cached segments can't be trusted when the server mis-answers ranges
+                Download::remove_cache_dir(&cache_dir)?;
+                return Err(e);
+            }
+            result => result?,
+        };
+
+        let prefix =
+            (start > output_offset).then_some((&target_filename, (start - output_offset) as u64));
+        stats.set_adopted(prefix.map_or(0, |(_, prefix_length)| prefix_length));
+        let part_handle = File::create(&part_filename)?;
+        if preallocate {
+            disk::preallocate(&part_handle, (end - output_offset) as u64)?;
+        }
+        let mut part_sink = FileSink::new(part_handle);
+        // the prefix and the segments are written in order, so the file is hashed on the way
+        let mut sink = DigestSink::new(&mut part_sink);
+        events.emit(DownloadEvent::AssemblyStarted {
+            path: target_filename.clone(),
+        });
+        Download::assemble(&mut sink, prefix, segments, output_offset)?;
+        let sha256 = sink.sha256();
+        part_sink.sync()?;
+        drop(part_sink);
+        match finalize::finalize_with_sha256(
+            &part_filename,
+            &target_filename,
+            Some((end - output_offset) as u64),
+            expected_sha256.as_deref(),
+            sha256,
+        ) {
+            // the cached segments produced a bad file, don't resume from them again
+            Err(e @ DownloadError::ChecksumMismatch(_)) => {
+                Download::remove_cache_dir(&cache_dir)?;
+                return Err(e);
+            }
+            result => {
+                let sha256 = result?;
+                events.emit(DownloadEvent::Verified {
+                    path: target_filename.clone(),
+                    sha256: sha256.clone(),
+                });
+                stats.set_sha256(sha256);
+            }
+        }
+        if !keep_cache {
+            //clean cache after all flushed
+            Download::remove_cache_dir(&cache_dir)?;
+        }
+        Ok(())
+    }
+
+    /// fetch `self.range` into memory
+    pub fn get_bytes(self) -> common::error::Result<Vec<u8>> {
+        if self.range.is_none() {
+            return Err(DownloadError::parameter("a byte range is required"));
+        }
+        let mut sink = MemorySink::new();
+        self.get_into(&mut sink)?;
+        Ok(sink.into_inner())
+    }
+
+    /// download the file (or `self.range`) straight into `sink`, segments write at their own
+    /// offsets so nothing goes through the cache dir. Without a cache there is no resume across
+    /// runs and `expected_sha256` is not checked. returns the number of bytes written
+    pub fn get_into(mut self, sink: &mut dyn StorageSink) -> common::error::Result<u64> {
+        let rt = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(self.worker_threads())
+            .thread_name("pget")
+            .enable_all()
+            .build()?;
+        let remote_info = rt.block_on(self.network.probe(&self.url))?;
+        let content_length_resp = remote_info.range_length();
+        // a refreshed url is checked against it
+        self.remote_info = Some(remote_info);
+        let (start, end) = match output_bounds(self.range.as_ref(), content_length_resp)? {
+            (start, Some(end)) => (start, end),
+            (_, None) => {
+                info!("the server doesn't report the length or ranges, fetching with one request");
+                let received = rt.block_on(Download::single_stream(
+                    &self.network,
+                    &self.url,
+                    &self.stats,
+                    sink,
+                    None,
+                ))?;
+                sink.finalize()?;
+                return Ok(received);
+            }
+        };
+
+        sink.set_len(end - start)?;
+        if end > start {
+            let network = self.network.clone();
+            let url = self.url.clone();
+            let stats = self.stats.clone();
+            let whole_file = self.range.is_none();
+            match self.request_into(&rt, sink, start as usize, end as usize) {
+                Err(DownloadError::UnexpectedRange(e)) if whole_file => {
+                    // the server does not honour ranges, fetch the file in one go
+                    warn!(error = ?e, "ranges are not honoured, falling back to one request");
+                    rt.block_on(Download::single_stream(
+                        &network,
+                        &url,
+                        &stats,
+                        sink,
+                        Some(end),
+                    ))?;
+                }
+                result => result?,
+            }
+        }
+        sink.finalize()?;
+        Ok(end - start)
+    }
+
+    /// fetch `[start, end)` over concurrent segments into `sink`, which starts at `start`
+    fn request_into(
+        self,
+        rt: &Runtime,
+        sink: &mut dyn StorageSink,
+        start: usize,
+        end: usize,
+    ) -> common::error::Result<()> {
+        let threads = if end - start <= self.options.small_file_threshold {
+            1
+        } else {
+            self.segment_count()
+        };
+        let bounds = segment_bounds(threads, start, end);
+        let tuner = self.tune_connections().map(|tuner| rt.spawn(tuner));
+        let mut progress = self.progress;
+        for (thread, (range_start, range_end)) in bounds.iter().enumerate() {
+            progress.add(range_end - range_start, thread + 1);
+            self.stats
+                .add_segment(thread + 1, *range_start as u64, *range_end as u64, 0);
+        }
+        let network = Arc::new(self.network);
+        let url = Arc::new(SegmentUrl::new(
+            self.url.clone(),
+            self.options.refresh_url.clone(),
+            self.remote_info.clone(),
+            Events::new(self.options.events.clone()),
+        ));
+        let writer = Writer::new();
+        let requests = bounds
+            .into_iter()
+            .enumerate()
+            .map(|(thread, (range_start, range_end))| {
+                rt.spawn(Self::request(
+                    writer.segment(0, start as u64, thread + 1),
+                    network.clone(),
+                    self.stats.clone(),
+                    Events::new(self.options.events.clone()),
+                    thread + 1,
+                    url.clone(),
+                    range_start,
+                    range_end,
+                    self.options.retries,
+                    self.options.stall.clone(),
+                ))
+            })
+            .collect();
+        let (written, results) = writer.drive(rt, requests, &mut [sink], &progress);
+        if let Some(tuner) = tuner {
+            tuner.abort();
+        }
+        written?;
+        Download::collect_segments(results)?;
+        Ok(())
+    }
+
+    /// the results of the joined segment tasks, or the error that decides how the download
+    /// goes on
+    fn collect_segments<T>(
+        results: Vec<Result<common::error::Result<T>, JoinError>>,
+    ) -> common::error::Result<Vec<T>> {
+        let mut segments = Vec::with_capacity(results.len());
+        let mut first_error = None;
+        for result in results {
+            match result {
+                Ok(Ok(segment)) => segments.push(segment),
+                // a range mismatch wins over other errors since it decides about the fallback
+                Ok(Err(e @ DownloadError::UnexpectedRange(_))) => return Err(e),
+                Ok(Err(e)) => {
+                    first_error.get_or_insert(e);
+                }
+                Err(e) => {
+                    first_error.get_or_insert(DownloadError::system(&e.to_string()));
+                }
+            }
+        }
+        match first_error {
+            Some(e) => Err(e),
+            None => Ok(segments),
+        }
+    }
+
+    /// download the whole body with one plain request, used when ranges are not available
+    async fn single_stream(
+        network: &Network,
+        url: &String,
+        stats: &TransferStats,
+        sink: &mut dyn StorageSink,
+        expected_length: Option<u64>,
+    ) -> common::error::Result<u64> {
+        let _permit = network.connection().await;
+        let resp = network.make_request(url, None).await?;
+        let mut resp = network.error_for_status(resp)?;
+        let expected_length = expected_length.or(resp.content_length());
+        // whatever segments ran before are replaced by this one request
+        stats.restart();
+        stats.add_segment(1, 0, expected_length.unwrap_or(0), 0);
+        stats.start_segment(1);
+        let fetched = stats.counter(1);
+        let mut sampled = Instant::now();
+        let mut received = 0;
+        while let Some(chunk) = resp.chunk().await? {
+            sink.write_at(received, &chunk)?;
+            received += chunk.len() as u64;
+            fetched.add(chunk.len() as u64);
+            // no checkpoints run here, the peak is sampled between the chunks
+            if sampled.elapsed() >= CHECKPOINT_INTERVAL {
+                stats.sample();
+                sampled = Instant::now();
+            }
+        }
+        sink.flush()?;
+        stats.finish_segment(1);
+        match expected_length {
+            Some(expected) if expected != received => Err(DownloadError::size_mismatch(&format!(
+                "expect {} bytes, received {}",
+                expected, received
+            ))),
+            _ => Ok(received),
+        }
+    }
+
+    /// [`Download::single_stream`] into a new part file, returns the bytes received and their sha256
+    async fn single_stream_to_file(
+        network: &Network,
+        url: &String,
+        stats: &TransferStats,
+        part_filename: &Path,
+        expected_length: Option<u64>,
+    ) -> common::error::Result<(u64, Option<String>)> {
+        let mut part_sink = FileSink::create(part_filename)?;
+        let mut sink = DigestSink::new(&mut part_sink);
+        let received =
+            Download::single_stream(network, url, stats, &mut sink, expected_length).await?;
+        let sha256 = sink.sha256();
+        part_sink.sync()?;
+        Ok((received, sha256))
+    }
+
+    /// how many segments the file is split into, `max` of the adaptive connections when set
+    pub(crate) fn segment_count(&self) -> usize {
+        match &self.options.adaptive_connections {
+            Some(adaptive) => adaptive.max.max(adaptive.min).max(1),
+            None => self.threads,
+        }
+    }
+
+    /// the runtime's worker threads, the connections are tasks and don't need one each
+    fn worker_threads(&self) -> usize {
+        let cores = std::thread::available_parallelism().map_or(4, |n| n.get());
+        self.segment_count().clamp(1, cores)
+    }
+
+    /// with adaptive connections: measures the throughput every interval and lets the network's
+    /// throttle pick the connection count, runs until it is aborted
+    fn tune_connections(&self) -> Option<impl Future<Output = ()>> {
+        let adaptive = self.options.adaptive_connections.clone()?;
+        let network = self.network.clone();
+        let stats = self.stats.clone();
+        network.throttle().adapt(adaptive.min, adaptive.max);
+        Some(async move {
+            let mut last = stats.fetched_bytes();
+            loop {
+                tokio::time::sleep(adaptive.interval).await;
+                let fetched = stats.fetched_bytes();
+                let bytes = fetched.saturating_sub(last);
+                network
+                    .throttle()
+                    .sample(bytes as f64 / adaptive.interval.as_secs_f64());
+                last = fetched;
+            }
+        })
+    }
+
+    fn cache_file_name(cache_dir: &Path, file_name: &str, thread_number: usize) -> PathBuf {
+        cache_dir.join(format!("{}.{}", file_name, thread_number))
+    }
+
+    fn cache_root(&self) -> common::error::Result<PathBuf> {
+        resolve_cache_root(&self.options.cache_location, &self.filename, |name| {
+            std::env::var_os(name)
+        })
+    }
+
+    /// the cache dir of this download, without creating it
+    pub(crate) fn cache_dir(&self) -> common::error::Result<PathBuf> {
+        self.cache_dir_from(0)
+    }
+
+    /// segments of a byte range, or of a download continuing at `start`, are laid out
+    /// differently, so they get a cache dir of their own
+    fn cache_dir_from(&self, start: usize) -> common::error::Result<PathBuf> {
+        let mut cache_key = match &self.options.cache_key {
+            Some(key) => key.clone(),
+            None => normalize_url(&self.url),
+        };
+        if let Some(range) = &self.range {
+            cache_key = format!("{}\nrange={}-{}", cache_key, range.start, range.end);
+        }
+        if start > self.range.as_ref().map_or(0, |range| range.start as usize) {
+            cache_key = format!("{}\nfrom={}", cache_key, start);
+        }
+        let hash_name = hash_string_to_hex(&cache_key);
+        Ok(self.cache_root()?.join(hash_name))
+    }
+
+    pub(crate) fn generate_cache_dir(&self, range_start: usize) -> common::error::Result<PathBuf> {
+        let cache_dir = self.cache_dir_from(range_start)?;
         if !cache_dir.exists() {
             fs::create_dir_all(&cache_dir)?;
         }
         Ok(cache_dir)
     }
 
+    /// drop the cached segments when the remote file changed since they were fetched, then
+    /// record the current version. the cache lock has to be held
+    pub(crate) fn check_remote_info(
+        cache_dir: &Path,
+        remote_info: &RemoteInfo,
+    ) -> common::error::Result<()> {
+        let remote_file = cache_dir.join(CACHE_REMOTE_FILE);
+        let cached = fs::read_to_string(&remote_file)
+            .ok()
+            .and_then(|content| serde_json::from_str::<RemoteInfo>(&content).ok());
+        if matches!(cached, Some(cached) if !cached.same_version(remote_info)) {
+            Download::remove_cache_dir(cache_dir)?;
+            fs::create_dir_all(cache_dir)?;
+        }
+        fs::write(remote_file, serde_json::to_string_pretty(remote_info)?)?;
+        Ok(())
+    }
+
+    fn remove_cache_dir(cache_dir: &Path) -> common::error::Result<()> {
+        fs::remove_dir_all(cache_dir)?;
+        Ok(())
+    }
+
+    /// remove the `.cache` dir next to the target once no download keeps anything there
+    fn remove_empty_cache_root(cache_dir: &Path) {
+        if let Some(root) = cache_dir.parent() {
+            if root.file_name() == Some(CACHE_PREFIX_PATH.as_ref()) {
+                // fails while other downloads still keep their cache there, which is fine
+                let _ = fs::remove_dir(root);
+            }
+        }
+    }
+
+    /// whether the existing `file_path` is what this download would write: by `expected_sha256`
+    /// when given, otherwise by the ETag recorded when it was downloaded. Without either the
+    /// size alone doesn't tell, it is downloaded again
+    fn matches_existing(&self, file_path: &Path) -> common::error::Result<bool> {
+        if self.options.expected_sha256.is_some() {
+            return self.matches_expected_sha256(file_path);
+        }
+        let Some(etag) = self
+            .remote_info
+            .as_ref()
+            .and_then(|remote| remote.etag.as_deref())
+        else {
+            return Ok(false);
+        };
+        let expected = finalize::version_key(etag, self.range.as_ref());
+        Ok(finalize::recorded_version(&self.version_path()?).as_deref() == Some(expected.as_str()))
+    }
+
+    fn matches_expected_sha256(&self, file_path: &Path) -> common::error::Result<bool> {
+        match &self.options.expected_sha256 {
+            Some(expected) => Ok(finalize::sha256_file(file_path)?.eq_ignore_ascii_case(expected)),
+            None => Ok(true),
+        }
+    }
+
+    fn target_dir(&self) -> common::error::Result<&Path> {
+        self.filename.parent().ok_or(DownloadError::parameter(
+            "target file should have a parent dir",
+        ))
+    }
+
+    fn target_lock_path(&self) -> common::error::Result<PathBuf> {
+        self.beside_target("lock")
+    }
+
+    /// the ETag the target file was downloaded with, see [`Download::matches_existing`]
+    pub(crate) fn version_path(&self) -> common::error::Result<PathBuf> {
+        self.beside_target("etag")
+    }
+
+    /// `.<file>.<extension>` next to the target file
+    fn beside_target(&self, extension: &str) -> common::error::Result<PathBuf> {
+        let file_name = self
+            .filename
+            .file_name()
+            .ok_or(DownloadError::parameter(
+                "target file should not be a director",
+            ))?
+            .to_string_lossy();
+        let file_dir = self.filename.parent().ok_or(DownloadError::parameter(
+            "target file should have a parent dir",
+        ))?;
+        Ok(file_dir.join(format!(".{}.{}", file_name, extension)))
+    }
+
+    /// lives next to the cache dir, so removing the cache doesn't pull it from under the holder
+    fn cache_lock_path(cache_dir: &Path) -> PathBuf {
+        cache_dir.with_extension("lock")
+    }
+
+    /// take the lock at `path` following the lock policy, the flag tells whether we had to wait
+    fn lock(&self, path: &Path) -> common::error::Result<(PathLock, bool)> {
+        if let Some(lock) = PathLock::try_acquire(path)? {
+            return Ok((lock, false));
+        }
+        match self.options.lock_policy {
+            LockPolicy::FailFast => Err(DownloadError::locked(&format!(
+                "{} is held by another download",
+                path.display()
+            ))),
+            LockPolicy::Wait | LockPolicy::Observe => Ok((PathLock::acquire(path)?, true)),
+        }
+    }
+
+    /// write `prefix` (the first bytes of an adopted file) and the cached segments into `file_path`,
+    /// remote byte `output_offset` becomes the first byte of the file
+    #[instrument(skip_all, fields(segments = ranges.len(), output_offset = output_offset))]
     fn assemble(
-        cache_dir: PathBuf,
-        file_path: PathBuf,
-        ranges: Vec<(String, usize, usize)>,
-        keep_cache: bool,
+        sink: &mut dyn StorageSink,
+        prefix: Option<(&PathBuf, u64)>,
+        ranges: Vec<(PathBuf, usize, usize)>,
+        output_offset: usize,
     ) -> common::error::Result<()> {
-        let origin_file_path_ref = file_path.clone();
-        let origin_file_handle = File::create(origin_file_path_ref)?;
-        let origin_file_arc = Arc::new(origin_file_handle);
-        for (cache_file_name, range_start, range_end) in &ranges {
-            let mut origin_file_ref = origin_file_arc.clone();
-            origin_file_ref.seek(SeekFrom::Start(*range_start as u64))?;
-            let mut cache_file_handle = File::open(&cache_file_name)?;
-            let mut writer = BufWriter::new(origin_file_ref);
-            copy_n_byte(&mut cache_file_handle, &mut writer, range_end - range_start)?;
-            writer.flush()?;
+        if let Some((prefix_file, prefix_length)) = prefix {
+            debug!(file = %prefix_file.display(), bytes = prefix_length, "copying the prefix");
+            let mut prefix_handle = BufReader::new(File::open(prefix_file)?);
+            copy_n_byte(&mut prefix_handle, sink, 0, prefix_length as usize)?;
         }
-        if !keep_cache {
-            //clean cache after all flushed
-            fs::remove_dir_all(cache_dir)?;
+        for (cache_file_name, range_start, range_end) in &ranges {
+            debug!(
+                file = %cache_file_name.display(),
+                range_start,
+                range_end,
+                bytes = range_end - range_start,
+                "copying a segment"
+            );
+            let mut cache_file_handle = BufReader::new(File::open(cache_file_name)?);
+            copy_n_byte(
+                &mut cache_file_handle,
+                sink,
+                (range_start - output_offset) as u64,
+                range_end - range_start,
+            )?;
         }
         Ok(())
     }
 
+    /// split `[start, content_length)` into segments and pick up their cached progress
     fn calculate_ranges(
         threads: usize,
+        start: usize,
         content_length: usize,
         mut progress: progress::Progress,
         cache_dir: PathBuf,
-    ) -> (
-        progress::Progress,
-        Vec<(Option<String>, usize, usize, usize)>,
-        HashMap<usize, DownloadProcess>,
-    ) {
-        let mut range_start = 0;
-        let mut ranges = vec![];
-        let chunk_size = content_length / threads - 1;
-
-        let record_status = Self::load_process(threads, cache_dir.clone());
+        file_name: &str,
+    ) -> (progress::Progress, Vec<Segment>) {
+        let segments = Self::cached_segments(threads, start, content_length, &cache_dir, file_name);
+        for segment in &segments {
+            let range_to_process = segment.range_end - segment.range_start;
+            progress.add(range_to_process, segment.thread_number);
+            match segment.query_start {
+                None => progress.finish(segment.thread_number),
+                Some(query_start) if query_start > segment.range_start => progress.set_position(
+                    (query_start - segment.range_start) as u64,
+                    segment.thread_number,
+                ),
+                Some(_) => (),
+            }
+        }
+        (progress, segments)
+    }
+
+    /// the segments of `[start, content_length)` with the progress recorded in `cache_dir`
+    fn cached_segments(
+        threads: usize,
+        start: usize,
+        content_length: usize,
+        cache_dir: &Path,
+        file_name: &str,
+    ) -> Vec<Segment> {
+        let bounds = segment_bounds(threads, start, content_length);
+        let record_status = Self::load_process(bounds.len(), cache_dir.to_path_buf());
         let mut map = HashMap::new();
         for record in record_status {
             map.insert(record.thread, record);
         }
 
-        for thread in 0..threads {
-            let mut range_end = chunk_size + range_start;
-            if thread == (threads - 1) {
-                range_end = content_length
-            }
-
+        let mut segments = vec![];
+        for (thread, (range_start, range_end)) in bounds.into_iter().enumerate() {
             let range_to_process = range_end - range_start;
 
             let thread_number = thread + 1;
-            let range_start_option = if let Some(process) = map.get(&thread_number) {
-                if process.cached_size as usize >= range_end - range_start {
-                    None
-                } else {
-                    Some(range_start + process.cached_size as usize)
+            // a status record without its cache file (or a stale one) can't be resumed from
+            let cached_size = match map.get(&thread_number) {
+                Some(process)
+                    if Self::cache_file_name(cache_dir, file_name, thread_number).exists() =>
+                {
+                    (process.cached_size as usize).min(range_to_process)
                 }
-            } else {
-                Some(range_start)
+                _ => 0,
             };
-            progress.add(range_to_process, thread_number);
-            if let Some(range_start_to_query) = range_start_option {
-                let range: String = format!("bytes={}-{}", range_start_to_query, range_end - 1);
-                // println!("   Thread: {}, range: {}, chunks: {}, chunk_remainder: {}", thread_number, range, buffer_chunks, chunk_remainder);
-                ranges.push((Some(range), range_start, thread_number, range_end));
+            let query_start = if cached_size >= range_to_process {
+                None
             } else {
-                ranges.push((None, range_start, thread_number, range_end));
-                progress.finish(thread_number);
-            }
-            if let Some(process) = map.get(&thread_number) {
-                if process.finished || progress.is_finished(thread_number) {
-                    progress.finish(thread_number);
-                } else {
-                    progress.set_position(process.cached_size, thread_number);
-                }
+                Some(range_start + cached_size)
             };
-
-            range_start = range_start + chunk_size;
+            segments.push(Segment {
+                thread_number,
+                range_start,
+                range_end,
+                query_start,
+            });
         }
-        return (progress, ranges, map);
+        segments
     }
 
+    /// fetch `[query_start, range_end)` and queue it on `writer`.
+    /// an early EOF or a broken connection is resumed from the last received byte, up to `retries` times
+    #[allow(clippy::too_many_arguments)]
+    #[instrument(
+        level = "debug",
+        skip_all,
+        fields(
+            url = %url_ref.get(),
+            thread_number = thread_number,
+            query_start = query_start,
+            range_end = range_end
+        )
+    )]
     async fn request(
-        file_handle: &mut File,
-        progress_ref: Arc<Progress>,
+        writer: SegmentWriter,
         network_ref: Arc<Network>,
+        stats_ref: Arc<TransferStats>,
+        events: Events,
+        thread_number: usize,
+        url_ref: Arc<SegmentUrl>,
+        query_start: usize,
+        range_end: usize,
+        retries: usize,
+        stall: Option<StallPolicy>,
+    ) -> common::error::Result<()> {
+        let mut offset = query_start;
+        let mut retry = Retries::new(&network_ref, &url_ref, retries);
+        stats_ref.start_segment(thread_number);
+        let fetched = stats_ref.counter(thread_number);
+        events.emit(DownloadEvent::SegmentStarted {
+            thread_number,
+            offset: query_start as u64,
+            end: range_end as u64,
+        });
+        loop {
+            // held while the response is read, a rate limit lowers how many may be open at once
+            let url = url_ref.get();
+            let permit = network_ref.connection().await;
+            let result = Self::request_once(
+                &writer,
+                &network_ref,
+                &fetched,
+                thread_number,
+                &url,
+                &mut offset,
+                range_end,
+                stall.as_ref(),
+            )
+            .await;
+            drop(permit);
+            let error = match result {
+                Ok(()) if offset == range_end => {
+                    // complete once the writer got through everything queued before
+                    writer.finish().await?;
+                    stats_ref.finish_segment(thread_number);
+                    debug!(bytes = range_end - query_start, "segment finished");
+                    events.emit(DownloadEvent::SegmentFinished {
+                        thread_number,
+                        fetched_bytes: (range_end - query_start) as u64,
+                    });
+                    return Ok(());
+                }
+                Ok(()) => DownloadError::size_mismatch(&format!(
+                    "thread #{} stopped at byte {} of {}",
+                    thread_number, offset, range_end
+                )),
+                Err(e) => e,
+            };
+            let scheduled = retry.schedule(error, &url, |scheduled| {
+                let (error, attempt, delay) =
+                    (&scheduled.error, scheduled.attempt, scheduled.delay);
+                warn!(error = ?error, offset, attempt, ?delay, "segment failed, retrying");
+                stats_ref.retry(thread_number);
+                events.emit(DownloadEvent::RetryScheduled {
+                    thread_number,
+                    attempt,
+                    offset: offset as u64,
+                    delay,
+                    error: format!("{:?}", error),
+                });
+            });
+            if let Err(e) = scheduled.await {
+                warn!(error = ?e, offset, "segment failed");
+                return Err(e);
+            }
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn request_once(
+        writer: &SegmentWriter,
+        network_ref: &Network,
+        fetched: &FetchCounter,
         thread_number: usize,
-        url_ref: String,
-        range: String,
+        url_ref: &String,
+        offset: &mut usize,
+        range_end: usize,
+        stall: Option<&StallPolicy>,
     ) -> common::error::Result<()> {
-        let mut file_range_resp = network_ref.make_request(&url_ref, Some(range)).await?;
-        while let Some(chunk) = file_range_resp.chunk().await? {
+        // a stalled response is given up on, the caller reconnects from `offset`
+        let mut watchdog = Watchdog::new(stall);
+        let mut file_range_resp = watchdog
+            .response(network_ref.request_range(url_ref, *offset as u64, range_end as u64))
+            .await?;
+        while let Some(chunk) = watchdog.chunk(&mut file_range_resp).await? {
             let buffer_size = chunk.len();
-            file_handle.write(&chunk)?;
-            file_handle.flush()?;
-            progress_ref.inc(buffer_size, thread_number);
+            if *offset + buffer_size > range_end {
+                return Err(DownloadError::unexpected_range(&format!(
+                    "thread #{} received more than {} bytes",
+                    thread_number, range_end
+                )));
+            }
+            // a full writer holds the segment back, that's not the response stalling
+            watchdog.paused(writer.write(*offset as u64, chunk)).await?;
+            *offset += buffer_size;
+            fetched.add(buffer_size as u64);
         }
 
         Ok(())
     }
 
     fn dump_process(
-        current: HashMap<&usize, (u64, bool)>,
+        current: HashMap<usize, (u64, bool)>,
         cached_dir: PathBuf,
     ) -> common::error::Result<()> {
         let mut process_status = Vec::new();
         for (key, (value, finished)) in current {
             process_status.push(DownloadProcess {
-                thread: *key,
+                thread: key,
                 cached_size: value,
                 finished,
             });
@@ -278,24 +1317,27 @@ impl Download {
         initial_status
     }
 
-    fn spawn_threads(
+    #[instrument(
+        skip_all,
+        fields(
+            cache_dir = %cache_dir.display(),
+            start = start,
+            content_length = content_length
+        )
+    )]
+    fn run_segments(
         self,
         rt: &Runtime,
+        cache_dir: PathBuf,
+        start: usize,
         content_length: usize,
-    ) -> common::error::Result<Vec<JoinHandle<common::error::Result<(String, usize, usize)>>>> {
+    ) -> common::error::Result<Vec<Result<SegmentResult, JoinError>>> {
         let mut children = vec![];
 
-        let cache_dir = self.generate_cache_dir()?;
+        let output_offset = self.range.as_ref().map_or(0, |range| range.start as usize);
+        let segment_count = self.segment_count();
         let network_arc = Arc::new(self.network);
 
-        let (progress, ranges, map) = Download::calculate_ranges(
-            self.threads,
-            content_length,
-            self.progress,
-            cache_dir.clone(),
-        );
-        let progress_arc = Arc::new(progress);
-
         let file_path = self.filename;
         let file_name = file_path
             .file_name()
@@ -306,71 +1348,727 @@ impl Download {
             .unwrap()
             .to_string();
 
-        let map_arc = Arc::new(map);
+        let (progress, segments) = Download::calculate_ranges(
+            segment_count,
+            start,
+            content_length,
+            self.progress,
+            cache_dir.clone(),
+            &file_name,
+        );
+        let progress_arc = Arc::new(progress);
+        let mut cached_bytes = 0;
+        for segment in &segments {
+            let cached_end = segment.query_start.unwrap_or(segment.range_end);
+            cached_bytes += (cached_end - segment.range_start) as u64;
+            self.stats.add_segment(
+                segment.thread_number,
+                segment.range_start as u64,
+                segment.range_end as u64,
+                (cached_end - segment.range_start) as u64,
+            );
+        }
+        info!(
+            segments = segments.len(),
+            cached_bytes, "resume state loaded"
+        );
+        let events = Events::new(self.options.events.clone());
+        let url = Arc::new(SegmentUrl::new(
+            self.url,
+            self.options.refresh_url.clone(),
+            self.remote_info,
+            events.clone(),
+        ));
+        events.emit(DownloadEvent::ResumeLoaded {
+            cache_dir: cache_dir.clone(),
+            cached_bytes,
+            total_bytes: (content_length - start) as u64,
+        });
 
-        for (range_opt, range_start, thread_number, range_end) in ranges.clone() {
-            let progress_ref = progress_arc.clone();
+        if self.options.check_disk_space {
+            // worst case: every missing byte lands in the cache, then the whole file in the part file
+            let cache_remaining: usize = segments
+                .iter()
+                .filter_map(|segment| segment.query_start.map(|start| segment.range_end - start))
+                .sum();
+            let target_dir = file_path.parent().ok_or(DownloadError::parameter(
+                "target file should have a parent dir",
+            ))?;
+            disk::check_space(&[
+                (&cache_dir, cache_remaining as u64),
+                (target_dir, (content_length - output_offset) as u64),
+            ])?;
+        }
+
+        // the cache files are opened and written on this thread, the runtime only waits on the network
+        let writer = Writer::new();
+        let mut cache_sinks = vec![];
+        for segment in segments {
+            let Segment {
+                thread_number,
+                range_start,
+                range_end,
+                query_start,
+            } = segment;
+            let cache_file_name = Self::cache_file_name(&cache_dir, &file_name, thread_number);
+            let query_start = query_start.filter(|_| !progress_arc.is_finished(thread_number));
+            let segment_writer = match query_start {
+                Some(_) => {
+                    let cache_file_handle = OpenOptions::new()
+                        .write(true)
+                        .create(true)
+                        .truncate(false)
+                        .open(&cache_file_name)?;
+                    let segment_length = (range_end - range_start) as u64;
+                    if self.options.preallocate {
+                        disk::preallocate(&cache_file_handle, segment_length)?;
+                    } else {
+                        let _ = cache_file_handle.set_len(segment_length);
+                    }
+                    cache_sinks.push(FileSink::new(cache_file_handle));
+                    Some(writer.segment(cache_sinks.len() - 1, range_start as u64, thread_number))
+                }
+                None => None,
+            };
             let network_ref = network_arc.clone();
-            let url_ref = self.url.clone();
-            let file_name_ref = file_name.clone();
-            let cache_path_ref = cache_dir.to_str().unwrap().to_string();
-            let map_ref = map_arc.clone();
-
-            children.push(rt.spawn(async move {
-                let cache_file_name = format!(
-                    "{}{}{}.{}",
-                    cache_path_ref,
-                    path::MAIN_SEPARATOR,
-                    file_name_ref,
-                    thread_number
-                );
-                if let Some(range) = range_opt {
-                    if !progress_ref.is_finished(thread_number) {
-                        let mut cache_file_handle = OpenOptions::new()
-                            .write(true)
-                            .create(true)
-                            .open(&cache_file_name)?;
-                        let _ = cache_file_handle.set_len((range_end - range_start) as u64);
-                        if let Some(process) = map_ref.get(&thread_number) {
-                            cache_file_handle.seek(SeekFrom::Start(process.cached_size as u64))?;
+            let stats_ref = self.stats.clone();
+            let events = events.clone();
+            let url_ref = url.clone();
+            let retries = self.options.retries;
+            let stall = self.options.stall.clone();
+
+            children.push(
+                rt.spawn(
+                    async move {
+                        if let (Some(query_start), Some(segment_writer)) =
+                            (query_start, segment_writer)
+                        {
+                            Self::request(
+                                segment_writer,
+                                network_ref,
+                                stats_ref,
+                                events,
+                                thread_number,
+                                url_ref,
+                                query_start,
+                                range_end,
+                                retries,
+                                stall,
+                            )
+                            .await?;
                         }
-                        Self::request(
-                            &mut cache_file_handle,
-                            progress_ref.clone(),
-                            network_ref,
-                            thread_number,
-                            url_ref,
-                            range,
-                        )
-                        .await?;
-                        progress_ref.finish(thread_number);
+                        Ok((cache_file_name, range_start, range_end))
                     }
-                }
-                return Ok((cache_file_name, range_start, range_end));
-            }));
+                    .in_current_span(),
+                ),
+            );
         }
 
         let status_checker = progress_arc.clone();
         let cache_dir_ref = cache_dir.clone();
-        rt.spawn(async move {
-            loop {
-                thread::sleep(Duration::from_secs(1));
-                let current = status_checker.dump();
-                let mut all_finished = true;
-                for (_, (_, is_finished)) in &current {
-                    all_finished &= is_finished;
-                }
-                if all_finished {
-                    break;
+        let stats = self.stats.clone();
+        let status_checker = rt.spawn(
+            async move {
+                let mut checkpoints = tokio::time::interval(CHECKPOINT_INTERVAL);
+                checkpoints.set_missed_tick_behavior(MissedTickBehavior::Delay);
+                // the first tick is immediate, nothing was written yet
+                checkpoints.tick().await;
+                loop {
+                    checkpoints.tick().await;
+                    stats.sample();
+                    let current: HashMap<usize, (u64, bool)> = status_checker
+                        .dump()
+                        .into_iter()
+                        .map(|(thread_number, status)| (*thread_number, status))
+                        .collect();
+                    if current.values().all(|(_, is_finished)| *is_finished) {
+                        break;
+                    }
+                    let cache_dir = cache_dir_ref.clone();
+                    let dumped =
+                        tokio::task::spawn_blocking(move || Self::dump_process(current, cache_dir))
+                            .await;
+                    if let Ok(Err(e)) = dumped {
+                        warn!(error = ?e, "failed to record the download progress");
+                    }
                 }
-                match Self::dump_process(current, cache_dir_ref.clone()) {
-                    Ok(_) => (),
-                    Err(e) => println!("dump process failed! error message = {:?}", e),
+            }
+            .in_current_span(),
+        );
+
+        let mut sinks: Vec<&mut dyn StorageSink> = cache_sinks
+            .iter_mut()
+            .map(|sink| sink as &mut dyn StorageSink)
+            .collect();
+        let (written, results) = writer.drive(rt, children, &mut sinks, &progress_arc);
+        status_checker.abort();
+        written?;
+        Ok(results)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::ffi::OsString;
+    use std::ops::Range;
+    use std::path::{Path, PathBuf};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+
+    use super::events::{DownloadEvent, Events};
+    use super::network::Network;
+    use super::refresh::SegmentUrl;
+    use super::report::TransferStats;
+    use super::sink::{MemorySink, OffsetSink};
+    use super::test_server::{Reply, Request, TestServer};
+    use super::writer::Writer;
+    use super::{
+        free_file_name, hash_string_to_hex, normalize_url, progress, resolve_cache_root,
+        segment_bounds, Download, CACHE_DIR_ENV,
+    };
+    use crate::common;
+    use crate::common::error::DownloadError;
+    use crate::common::options::{
+        CacheLocation, DownloadOptions, ExistingFilePolicy, LockPolicy, UrlRefresh,
+    };
+
+    /// fetch `[0, len)` of `url` as segment #1 into memory, returns how the segment ended, the
+    /// bytes written and whether the progress counts the segment as finished
+    fn fetch_segment(
+        url: &str,
+        len: usize,
+        retries: usize,
+    ) -> (common::error::Result<()>, Vec<u8>, bool) {
+        let rt = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .enable_all()
+            .build()
+            .unwrap();
+        let mut progress = progress::Progress::default();
+        progress.add(len, 1);
+        let writer = Writer::new();
+        let events = Events::new(None);
+        let request = rt.spawn(Download::request(
+            writer.segment(0, 0, 1),
+            Arc::new(Network::default()),
+            Arc::new(TransferStats::default()),
+            events.clone(),
+            1,
+            Arc::new(SegmentUrl::new(url.to_string(), None, None, events)),
+            0,
+            len,
+            retries,
+            None,
+        ));
+        let mut sink = MemorySink::new();
+        let (written, mut results) = writer.drive(&rt, vec![request], &mut [&mut sink], &progress);
+        written.unwrap();
+        let result = results.pop().unwrap().unwrap();
+        (result, sink.into_inner(), progress.is_finished(1))
+    }
+
+    #[test]
+    fn test_short_segment_is_not_finished() {
+        let data: Vec<u8> = (0..4000u32).map(|i| i as u8).collect();
+
+        // the server ends the response early but cleanly
+        let served = data.clone();
+        let server = TestServer::start(move |request| Reply::file(&served, request).truncate(1000));
+        let (result, _, finished) = fetch_segment(&server.url("/short.bin"), data.len(), 0);
+        assert!(matches!(result, Err(DownloadError::SizeMismatch(_))));
+        assert!(!finished);
+
+        // with a retry the rest is requested from where the short response stopped
+        let served = data.clone();
+        let first = AtomicBool::new(true);
+        let server = TestServer::start(move |request| {
+            let reply = Reply::file(&served, request);
+            if first.swap(false, Ordering::SeqCst) {
+                reply.truncate(1000)
+            } else {
+                reply
+            }
+        });
+        let (result, received, finished) = fetch_segment(&server.url("/short.bin"), data.len(), 1);
+        result.unwrap();
+        assert_eq!(received, data);
+        assert!(finished);
+        assert_eq!(server.requests(), 2);
+    }
+
+    #[test]
+    fn test_refused_segment_is_refreshed() {
+        let data: Vec<u8> = (0..64 * 1024u32).map(|i| (i % 251) as u8).collect();
+
+        // without a callback the original url is probed again, and asked after a delay
+        let served = data.clone();
+        let first = AtomicBool::new(true);
+        let server = TestServer::start(move |request| match first.swap(false, Ordering::SeqCst) {
+            true => Reply::status(403),
+            false => Reply::file(&served, request),
+        });
+        let started = Instant::now();
+        let (result, received, finished) = fetch_segment(&server.url("/m.bin"), data.len(), 1);
+        result.unwrap();
+        assert_eq!(received, data);
+        assert!(finished);
+        // refused, probed, requested again
+        assert_eq!(server.requests(), 3);
+        assert!(started.elapsed() >= Duration::from_secs(1));
+
+        // a callback hands out the fresh link, the segments continue with it
+        let served = data.clone();
+        let paths = Arc::new(Mutex::new(Vec::new()));
+        let log = paths.clone();
+        let server = TestServer::start(move |request| {
+            log.lock().unwrap().push(request.path.clone());
+            let probe = request.range == Some((0, Some(0)));
+            match request.path.as_str() {
+                "/m.bin?sig=old" if !probe => Reply::status(403),
+                _ => Reply::file(&served, request),
+            }
+        });
+        let fresh = server.url("/m.bin?sig=new");
+        let temp = tempfile::tempdir().unwrap();
+        let target = temp.path().join("m.bin");
+        let mut download =
+            local_download(&server.url("/m.bin?sig=old"), target.clone(), temp.path());
+        download.options.small_file_threshold = 1024;
+        download.options.refresh_url = Some(UrlRefresh::new(move |_| Some(fresh.clone())));
+        download.get().unwrap();
+        assert_eq!(std::fs::read(&target).unwrap(), data);
+        assert!(paths
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|path| path == "/m.bin?sig=new"));
+    }
+
+    /// a download of `url` into `filename` with its cache below `cache`
+    fn local_download(url: &str, filename: PathBuf, cache: &Path) -> Download {
+        Download {
+            url: url.to_string(),
+            filename,
+            options: DownloadOptions {
+                cache_location: CacheLocation::Dir(cache.to_path_buf()),
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_skip_if_match_compares_etag() {
+        let remote = Arc::new(Mutex::new((b"first".to_vec(), Some("\"e1\""))));
+        let served = remote.clone();
+        let server = TestServer::start(move |request| {
+            let (data, etag) = &*served.lock().unwrap();
+            let reply = Reply::file(data, request);
+            match etag {
+                Some(etag) => reply.header("ETag", etag),
+                None => reply,
+            }
+        });
+        let temp = tempfile::tempdir().unwrap();
+        let target = temp.path().join("file.txt");
+        let download = || {
+            let mut download =
+                local_download(&server.url("/file.txt"), target.clone(), temp.path());
+            download.options.existing_file = ExistingFilePolicy::SkipIfMatch;
+            download.get().unwrap()
+        };
+
+        // a file of the same size that pget didn't download isn't trusted
+        std::fs::write(&target, b"stale").unwrap();
+        download();
+        assert_eq!(std::fs::read(&target).unwrap(), b"first");
+
+        // kept while the ETag stays the same
+        let requests = server.requests();
+        assert_eq!(download().fetched_bytes, 0);
+        assert_eq!(server.requests(), requests + 1);
+
+        // same size, new ETag
+        *remote.lock().unwrap() = (b"other".to_vec(), Some("\"e2\""));
+        download();
+        assert_eq!(std::fs::read(&target).unwrap(), b"other");
+        let version_path = temp.path().join(".file.txt.etag");
+        assert!(version_path.exists());
+
+        // other policies leave nothing beside the file, and drop what is recorded
+        local_download(&server.url("/file.txt"), target.clone(), temp.path())
+            .get()
+            .unwrap();
+        assert!(!version_path.exists());
+        // so the next `SkipIfMatch` doesn't trust the file
+        assert_eq!(download().fetched_bytes, 5);
+        let mut names = std::fs::read_dir(temp.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(names, [".file.txt.etag", "file.txt"]);
+
+        // without an ETag it is downloaded again every time
+        *remote.lock().unwrap() = (b"third".to_vec(), None);
+        download();
+        assert_eq!(std::fs::read(&target).unwrap(), b"third");
+        let requests = server.requests();
+        assert_eq!(download().fetched_bytes, 5);
+        assert!(server.requests() > requests + 1);
+    }
+
+    #[test]
+    fn test_range_download() {
+        let data: Vec<u8> = (0..64 * 1024u32).map(|i| (i % 251) as u8).collect();
+        let served = data.clone();
+        let requested = Arc::new(Mutex::new(Vec::new()));
+        let log = requested.clone();
+        let server = TestServer::start(move |request| {
+            log.lock().unwrap().push(request.range);
+            Reply::file(&served, request)
+        });
+        let url = server.url("/data.bin");
+        let temp = tempfile::tempdir().unwrap();
+        let target = temp.path().join("part.bin");
+        let ranged = |range: Range<u64>| {
+            let mut download = local_download(&url, target.clone(), temp.path());
+            download.range = Some(range);
+            download.options.small_file_threshold = 1024;
+            download
+        };
+
+        assert!(matches!(
+            ranged(10..10).get(),
+            Err(DownloadError::ParameterError(_))
+        ));
+        assert!(matches!(
+            ranged(0..data.len() as u64 + 1).get(),
+            Err(DownloadError::ParameterError(_))
+        ));
+        assert!(!target.exists());
+
+        // an earlier run of the same range finished its first segment
+        let download = ranged(1000..41000);
+        let cache_dir = download.cache_dir_from(1000).unwrap();
+        assert_eq!(
+            cache_dir,
+            temp.path().join(hash_string_to_hex(&format!(
+                "{}\nrange=1000-41000",
+                normalize_url(&url)
+            )))
+        );
+        let bounds = segment_bounds(download.threads, 1000, 41000);
+        let (first_start, first_end) = bounds[0];
+        std::fs::create_dir_all(&cache_dir).unwrap();
+        std::fs::write(
+            Download::cache_file_name(&cache_dir, "part.bin", 1),
+            &data[first_start..first_end],
+        )
+        .unwrap();
+        let status = (1..=bounds.len())
+            .map(|thread| match thread {
+                1 => (thread, ((first_end - first_start) as u64, true)),
+                _ => (thread, (0, false)),
+            })
+            .collect();
+        Download::dump_process(status, cache_dir.clone()).unwrap();
+
+        let before = requested.lock().unwrap().len();
+        let report = download.get().unwrap();
+        assert_eq!(std::fs::read(&target).unwrap(), &data[1000..41000]);
+        assert_eq!(report.resumed_bytes, (first_end - first_start) as u64);
+        // the probe and the other segments, the cached one isn't requested again
+        let requested = requested.lock().unwrap()[before..].to_vec();
+        assert_eq!(requested.len(), bounds.len());
+        assert!(requested
+            .iter()
+            .all(|range| !matches!(range, Some((start, _)) if *start == first_start as u64)));
+    }
+
+    #[test]
+    fn test_download_into_sinks() {
+        let data: Vec<u8> = (0..64 * 1024u32).map(|i| (i % 251) as u8).collect();
+        let served = data.clone();
+        let server = TestServer::start(move |request| Reply::file(&served, request));
+        let url = server.url("/data.bin");
+        let options = || DownloadOptions {
+            small_file_threshold: 1024,
+            ..Default::default()
+        };
+
+        let mut memory = MemorySink::new();
+        let written = crate::download_to_sink(&url, 4, &mut memory, options()).unwrap();
+        assert_eq!(written, data.len() as u64);
+        assert_eq!(memory.into_inner(), data);
+
+        // one range of the file into the middle of a pack file, the rest stays as it was
+        let temp = tempfile::tempdir().unwrap();
+        let pack = temp.path().join("pack.bin");
+        std::fs::write(&pack, vec![0xff; 50_000]).unwrap();
+        let mut sink = OffsetSink::open(&pack, 100).unwrap();
+        let download = Download {
+            url: url.clone(),
+            threads: 4,
+            range: Some(1000..41000),
+            options: options(),
+            ..Default::default()
+        };
+        assert_eq!(download.get_into(&mut sink).unwrap(), 40000);
+        let packed = std::fs::read(&pack).unwrap();
+        assert_eq!(packed.len(), 50_000);
+        assert!(packed[..100].iter().all(|byte| *byte == 0xff));
+        assert_eq!(&packed[100..40100], &data[1000..41000]);
+        assert!(packed[40100..].iter().all(|byte| *byte == 0xff));
+    }
+
+    #[test]
+    fn test_sink_falls_back_when_ranges_are_ignored() {
+        let data: Vec<u8> = (0..64 * 1024u32).map(|i| (i % 251) as u8).collect();
+        let served = data.clone();
+        let requested = Arc::new(Mutex::new(Vec::new()));
+        let log = requested.clone();
+        // the probe is answered with a range, the segments get the whole file
+        let server = TestServer::start(move |request| {
+            log.lock().unwrap().push(request.range);
+            match request.range {
+                Some((0, Some(0))) => Reply::file(&served, request),
+                _ => {
+                    let whole = Request {
+                        path: request.path.clone(),
+                        range: None,
+                    };
+                    Reply::file(&served, &whole)
                 }
             }
         });
+        let mut sink = MemorySink::new();
+        let options = DownloadOptions {
+            small_file_threshold: 1024,
+            ..Default::default()
+        };
+        let written =
+            crate::download_to_sink(server.url("/data.bin"), 4, &mut sink, options).unwrap();
+        assert_eq!(written, data.len() as u64);
+        assert_eq!(sink.into_inner(), data);
+        // the file was fetched again with one plain request
+        assert!(requested.lock().unwrap().iter().any(Option::is_none));
+    }
+
+    #[test]
+    fn test_download_events() {
+        let data: Vec<u8> = (0..64 * 1024u32).map(|i| (i % 251) as u8).collect();
+        let served = data.clone();
+        let server = TestServer::start(move |request| Reply::file(&served, request));
+        let temp = tempfile::tempdir().unwrap();
+        let target = temp.path().join("data.bin");
+        // written differently than the client reports it back, but not redirected
+        let url = server.url("/data.bin#part").replacen("http", "HTTP", 1);
+        let (sender, mut receiver) = tokio::sync::broadcast::channel(64);
+        let mut download = local_download(&url, target.clone(), temp.path());
+        download.threads = 4;
+        download.options.small_file_threshold = 1024;
+        download.options.events = Some(sender);
+        download.get().unwrap();
+        assert_eq!(std::fs::read(&target).unwrap(), data);
+
+        let mut events = Vec::new();
+        while let Ok(event) = receiver.try_recv() {
+            events.push(event);
+        }
+        assert!(matches!(
+            &events[0],
+            DownloadEvent::Probed { remote } if remote.size == Some(data.len() as u64)
+        ));
+        assert!(matches!(
+            &events[1],
+            DownloadEvent::ResumeLoaded { cached_bytes: 0, total_bytes, .. }
+                if *total_bytes == data.len() as u64
+        ));
+        let segments = &events[2..events.len() - 2];
+        assert_eq!(segments.len(), 8);
+        for thread in 1..=4 {
+            let started = segments.iter().position(|event| match event {
+                DownloadEvent::SegmentStarted { thread_number, .. } => *thread_number == thread,
+                _ => false,
+            });
+            let finished = segments.iter().position(|event| match event {
+                DownloadEvent::SegmentFinished { thread_number, .. } => *thread_number == thread,
+                _ => false,
+            });
+            assert!(started.unwrap() < finished.unwrap());
+        }
+        assert_eq!(
+            events[events.len() - 2],
+            DownloadEvent::AssemblyStarted {
+                path: target.clone()
+            }
+        );
+        assert!(matches!(
+            &events[events.len() - 1],
+            DownloadEvent::Verified { path, sha256: Some(_) } if *path == target
+        ));
+    }
+
+    #[test]
+    fn test_free_file_name() {
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path();
+        std::fs::write(dir.join("model.1.bin"), b"").unwrap();
+        assert_eq!(
+            free_file_name(&dir.join("model.bin")),
+            dir.join("model.2.bin")
+        );
+        assert_eq!(free_file_name(&dir.join("README")), dir.join("README.1"));
+    }
+
+    #[test]
+    fn test_cache_key_is_stable() {
+        assert_eq!(
+            normalize_url("HTTPS://Example.COM:443/a/b.bin?x=1#part"),
+            "https://example.com/a/b.bin?x=1"
+        );
+        assert_eq!(
+            hash_string_to_hex("https://example.com/a/b.bin?x=1"),
+            "cf432dd99cf5f3e8192ed687dae135ba7c9302cb740afb0a2924c178972ae434"
+        );
+        assert_eq!(
+            normalize_url(
+                "https://bucket.s3.amazonaws.com/m.bin?response-content-type=a%2Fb\
+                 &X-Amz-Algorithm=AWS4-HMAC-SHA256&X-Amz-Expires=3600&X-Amz-Signature=abc"
+            ),
+            "https://bucket.s3.amazonaws.com/m.bin?response-content-type=a%2Fb"
+        );
+        assert_eq!(
+            normalize_url("https://cdn.example.com/m.bin?Expires=1&Signature=x&Key-Pair-Id=K"),
+            "https://cdn.example.com/m.bin"
+        );
+        assert_eq!(
+            normalize_url(
+                "https://storage.googleapis.com/b/m.bin?generation=7&X-Goog-Algorithm=GOOG4-RSA\
+                 &X-Goog-Expires=600&X-Goog-Signature=abc"
+            ),
+            "https://storage.googleapis.com/b/m.bin?generation=7"
+        );
+        assert_eq!(
+            normalize_url(
+                "https://s3.amazonaws.com/b/m.bin?AWSAccessKeyId=A&Expires=1&Signature=x"
+            ),
+            "https://s3.amazonaws.com/b/m.bin"
+        );
+        // without a signature these are ordinary parameters and pick another file
+        for url in [
+            "https://example.com/m.bin?expires=10&signature=x",
+            "https://example.com/m.bin?policy=p&Key-Pair-Id=K",
+            "https://example.com/m.bin?x-amz-meta=1&x-goog-meta=2",
+        ] {
+            assert_eq!(
+                normalize_url(url),
+                reqwest::Url::parse(url).unwrap().to_string()
+            );
+        }
+        // a GCS signature leaves S3 parameters alone
+        assert_eq!(
+            normalize_url("https://example.com/m.bin?x-amz-date=1&X-Goog-Signature=abc"),
+            "https://example.com/m.bin?x-amz-date=1"
+        );
+        assert_ne!(
+            normalize_url("https://example.com/m.bin?expires=10"),
+            normalize_url("https://example.com/m.bin?expires=20")
+        );
+    }
+
+    #[test]
+    fn test_lock_fail_fast() {
+        let temp = tempfile::tempdir().unwrap();
+        let path = temp.path().join("model.bin.lock");
+        let download = Download {
+            options: DownloadOptions {
+                lock_policy: LockPolicy::FailFast,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let (lock, waited) = download.lock(&path).unwrap();
+        assert!(!waited);
+        assert!(matches!(
+            download.lock(&path),
+            Err(DownloadError::Locked(_))
+        ));
+        drop(lock);
+        assert!(download.lock(&path).is_ok());
+    }
 
-        // progress_arc.clone().join_and_clear();
-        return Ok(children);
+    #[test]
+    fn test_cache_root() {
+        let filename = Path::new("/data/model.bin");
+        let cache_root = |cache_location, vars: &[(&str, &str)]| {
+            resolve_cache_root(&cache_location, filename, |name| {
+                vars.iter()
+                    .find(|(var, _)| *var == name)
+                    .map(|(_, value)| OsString::from(value))
+            })
+        };
+        let env = [
+            (CACHE_DIR_ENV, "/var/cache/pget"),
+            ("XDG_CACHE_HOME", "/xdg"),
+            ("HOME", "/home/user"),
+        ];
+        assert_eq!(
+            cache_root(CacheLocation::Default, &env).unwrap(),
+            PathBuf::from("/var/cache/pget")
+        );
+        assert_eq!(
+            cache_root(CacheLocation::BesideTarget, &env).unwrap(),
+            PathBuf::from("/data/.cache")
+        );
+        assert_eq!(
+            cache_root(CacheLocation::Xdg, &env).unwrap(),
+            PathBuf::from("/xdg/pget")
+        );
+        assert_eq!(
+            cache_root(CacheLocation::Dir(PathBuf::from("/elsewhere")), &env).unwrap(),
+            PathBuf::from("/elsewhere")
+        );
+
+        // an empty PGET_CACHE_DIR and a relative XDG_CACHE_HOME don't count
+        let env = [
+            (CACHE_DIR_ENV, ""),
+            ("XDG_CACHE_HOME", "relative"),
+            ("HOME", "/home/user"),
+        ];
+        assert_eq!(
+            cache_root(CacheLocation::Default, &env).unwrap(),
+            PathBuf::from("/data/.cache")
+        );
+        assert_eq!(
+            cache_root(CacheLocation::Xdg, &env).unwrap(),
+            PathBuf::from("/home/user/.cache/pget")
+        );
+        assert!(cache_root(CacheLocation::Xdg, &[]).is_err());
+    }
+
+    #[test]
+    fn test_calculate_ranges_covers_content() {
+        for (threads, content_length) in [(8, 1), (8, 3), (8, 15), (4, 16), (3, 1000), (1, 7)] {
+            let (_, segments) = Download::calculate_ranges(
+                threads,
+                0,
+                content_length,
+                progress::Progress::default(),
+                PathBuf::from("not-exist-cache-dir"),
+                "file",
+            );
+            assert!(!segments.is_empty() && segments.len() <= threads);
+            let mut expected_start = 0;
+            for segment in &segments {
+                assert_eq!(segment.range_start, expected_start);
+                assert!(segment.range_end > segment.range_start);
+                assert_eq!(segment.query_start, Some(segment.range_start));
+                expected_start = segment.range_end;
+            }
+            assert_eq!(expected_start, content_length);
+        }
     }
 }
@@ -0,0 +1,126 @@
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::common;
+use crate::common::error::DownloadError;
+
+/// make sure every filesystem has room for the bytes that still have to land on it.
+/// `requirements` pairs a dir with the bytes written below it, dirs on one filesystem add up.
+pub(crate) fn check_space(requirements: &[(&Path, u64)]) -> common::error::Result<()> {
+    let mut filesystems: Vec<(Option<u64>, PathBuf, u64)> = Vec::new();
+    for (dir, bytes) in requirements {
+        let dir = existing_ancestor(dir);
+        let id = filesystem_id(&dir)?;
+        match filesystems
+            .iter_mut()
+            .find(|(other, _, _)| other.is_some() && *other == id)
+        {
+            Some((_, _, required)) => *required += bytes,
+            None => filesystems.push((id, dir, *bytes)),
+        }
+    }
+    for (_, dir, required) in filesystems {
+        if let Some(available) = available_space(&dir)? {
+            if available < required {
+                return Err(DownloadError::insufficient_space(&format!(
+                    "{} bytes are needed on the filesystem of {}, only {} are available",
+                    required,
+                    dir.display(),
+                    available
+                )));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// reserve `len` bytes for `file` so running out of space fails now rather than halfway
+#[cfg(target_os = "linux")]
+pub(crate) fn preallocate(file: &File, len: u64) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    if len == 0 {
+        return Ok(());
+    }
+    let result = unsafe { libc::fallocate(file.as_raw_fd(), 0, 0, len as libc::off_t) };
+    if result == 0 {
+        return Ok(());
+    }
+    let error = io::Error::last_os_error();
+    match error.raw_os_error() {
+        // the filesystem can't reserve blocks, settle for the file size
+        Some(libc::EOPNOTSUPP) => file.set_len(len.max(file.metadata()?.len())),
+        _ => Err(error),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn preallocate(file: &File, len: u64) -> io::Result<()> {
+    file.set_len(len.max(file.metadata()?.len()))
+}
+
+fn existing_ancestor(dir: &Path) -> PathBuf {
+    let mut dir = if dir.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        dir
+    };
+    while !dir.exists() {
+        match dir.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => dir = parent,
+            _ => return PathBuf::from("."),
+        }
+    }
+    dir.to_path_buf()
+}
+
+#[cfg(unix)]
+fn filesystem_id(dir: &Path) -> io::Result<Option<u64>> {
+    use std::os::unix::fs::MetadataExt;
+
+    Ok(Some(std::fs::metadata(dir)?.dev()))
+}
+
+#[cfg(not(unix))]
+fn filesystem_id(_dir: &Path) -> io::Result<Option<u64>> {
+    Ok(None)
+}
+
+#[cfg(unix)]
+fn available_space(dir: &Path) -> io::Result<Option<u64>> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = CString::new(dir.as_os_str().as_bytes())?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    #[allow(clippy::unnecessary_cast)]
+    Ok(Some(stat.f_bavail as u64 * stat.f_frsize as u64))
+}
+
+/// free space is not queried on other platforms, the download fails on write instead
+#[cfg(not(unix))]
+fn available_space(_dir: &Path) -> io::Result<Option<u64>> {
+    Ok(None)
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use super::check_space;
+    use crate::common::error::DownloadError;
+
+    #[test]
+    fn test_check_space() {
+        let dir = std::env::temp_dir();
+        check_space(&[(&dir, 1), (Path::new("not/exist/yet"), 1)]).unwrap();
+        let result = check_space(&[(&dir, u64::MAX / 2), (&dir, u64::MAX / 2)]);
+        if cfg!(unix) {
+            assert!(matches!(result, Err(DownloadError::InsufficientSpace(_))));
+        }
+    }
+}
@@ -0,0 +1,93 @@
+use std::path::PathBuf;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+
+use super::network::RemoteInfo;
+
+/// Something that happened during a download, published on
+/// [`DownloadOptions::events`](crate::common::options::DownloadOptions::events).
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum DownloadEvent {
+    /// the server answered the probe
+    Probed { remote: RemoteInfo },
+    /// the probe was redirected, requests follow the same redirects
+    Redirected { from: String, to: String },
+    /// the cached progress of an earlier run was read from `cache_dir`
+    ResumeLoaded {
+        cache_dir: PathBuf,
+        cached_bytes: u64,
+        total_bytes: u64,
+    },
+    /// segment `thread_number` starts fetching `[offset, end)`
+    SegmentStarted {
+        thread_number: usize,
+        offset: u64,
+        end: u64,
+    },
+    SegmentFinished {
+        thread_number: usize,
+        fetched_bytes: u64,
+    },
+    /// the url was refused with `401` or `403`, segments continue with `to` from now on
+    UrlRefreshed { from: String, to: String },
+    /// the segment failed at `offset` and is requested again after `delay`
+    RetryScheduled {
+        thread_number: usize,
+        attempt: usize,
+        offset: u64,
+        delay: Duration,
+        error: String,
+    },
+    /// the segments are being copied into the part file of `path`
+    AssemblyStarted { path: PathBuf },
+    /// the file passed the size and checksum checks and was moved to `path`
+    Verified {
+        path: PathBuf,
+        sha256: Option<String>,
+    },
+}
+
+/// publishes on the channel of the download's options, does nothing without one
+#[derive(Clone, Default)]
+pub(crate) struct Events {
+    sender: Option<broadcast::Sender<DownloadEvent>>,
+}
+
+impl Events {
+    pub fn new(sender: Option<broadcast::Sender<DownloadEvent>>) -> Events {
+        Events { sender }
+    }
+
+    pub fn emit(&self, event: DownloadEvent) {
+        if let Some(sender) = &self.sender {
+            // nobody listening right now is fine, the download goes on regardless
+            let _ = sender.send(event);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::sync::broadcast;
+
+    use super::{DownloadEvent, Events};
+
+    #[test]
+    fn test_events() {
+        let event = DownloadEvent::SegmentFinished {
+            thread_number: 1,
+            fetched_bytes: 3,
+        };
+        Events::default().emit(event.clone());
+
+        let (sender, mut receiver) = broadcast::channel(4);
+        let events = Events::new(Some(sender));
+        events.emit(event.clone());
+        assert_eq!(receiver.try_recv().unwrap(), event);
+        drop(receiver);
+        // no receiver left, emitting still works
+        events.emit(event);
+    }
+}
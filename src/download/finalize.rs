@@ -0,0 +1,200 @@
+use std::fs::{self, File};
+use std::io::Read;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use sha2::{Digest, Sha256};
+
+use crate::common;
+use crate::common::error::DownloadError;
+
+/// the temporary name a file is written under until it is complete, in the target's own dir
+/// so the final rename never crosses filesystems
+pub(crate) fn part_path(file_path: &Path) -> common::error::Result<PathBuf> {
+    let file_name = file_path.file_name().ok_or(DownloadError::parameter(
+        "target file should not be a director",
+    ))?;
+    let mut part_name = file_name.to_os_string();
+    part_name.push(".part");
+    Ok(file_path.with_file_name(part_name))
+}
+
+/// what a finished file is recorded as: the remote ETag, plus the bytes it holds for a range
+pub(crate) fn version_key(etag: &str, range: Option<&Range<u64>>) -> String {
+    match range {
+        Some(range) => format!("{}\nrange={}-{}", etag, range.start, range.end),
+        None => etag.to_string(),
+    }
+}
+
+/// keep `version` at `version_path` next to a finished file, `None` forgets an older one
+pub(crate) fn record_version(
+    version_path: &Path,
+    version: Option<String>,
+) -> common::error::Result<()> {
+    match version {
+        Some(version) => fs::write(version_path, version)?,
+        None => match fs::remove_file(version_path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+            _ => (),
+        },
+    }
+    Ok(())
+}
+
+pub(crate) fn recorded_version(version_path: &Path) -> Option<String> {
+    fs::read_to_string(version_path).ok()
+}
+
+pub(crate) fn check_size(file_path: &Path, expected: u64) -> common::error::Result<()> {
+    let actual = fs::metadata(file_path)?.len();
+    if actual != expected {
+        return Err(DownloadError::size_mismatch(&format!(
+            "{} should be {} bytes, found {}",
+            file_path.display(),
+            expected,
+            actual
+        )));
+    }
+    Ok(())
+}
+
+pub(crate) fn sha256_file(file_path: &Path) -> common::error::Result<String> {
+    let mut hasher = Sha256::new();
+    let mut file = File::open(file_path)?;
+    let mut buffer = vec![0u8; 64 * 1024];
+    loop {
+        let count = file.read(&mut buffer)?;
+        if count == 0 {
+            break;
+        }
+        hasher.update(&buffer[..count]);
+    }
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// check the finished `.part` file and move it to `file_path`. The writer syncs the data
+/// before, the rename is synced before returning, so `file_path` only ever holds a complete
+/// file. A part file failing the checks is removed.
+pub(crate) fn finalize(
+    part_path: &Path,
+    file_path: &Path,
+    expected_length: Option<u64>,
+    expected_sha256: Option<&str>,
+) -> common::error::Result<()> {
+    finalize_with_sha256(part_path, file_path, expected_length, expected_sha256, None)?;
+    Ok(())
+}
+
+/// [`finalize`] with the `sha256` of the part file when it was hashed while being written, so
+/// it isn't read again. returns the sha256 when it is known
+pub(crate) fn finalize_with_sha256(
+    part_path: &Path,
+    file_path: &Path,
+    expected_length: Option<u64>,
+    expected_sha256: Option<&str>,
+    sha256: Option<String>,
+) -> common::error::Result<Option<String>> {
+    let sha256 = match verify(part_path, expected_length, expected_sha256, sha256) {
+        Ok(sha256) => sha256,
+        Err(e) => {
+            let _ = fs::remove_file(part_path);
+            return Err(e);
+        }
+    };
+    fs::rename(part_path, file_path)?;
+    sync_parent_dir(file_path)?;
+    Ok(sha256)
+}
+
+fn verify(
+    part_path: &Path,
+    expected_length: Option<u64>,
+    expected_sha256: Option<&str>,
+    sha256: Option<String>,
+) -> common::error::Result<Option<String>> {
+    if let Some(expected_length) = expected_length {
+        check_size(part_path, expected_length)?;
+    }
+    if let Some(expected_sha256) = expected_sha256 {
+        let actual = match sha256 {
+            Some(sha256) => sha256,
+            None => sha256_file(part_path)?,
+        };
+        if !actual.eq_ignore_ascii_case(expected_sha256) {
+            return Err(DownloadError::checksum_mismatch(&format!(
+                "sha256 of {} should be {}, found {}",
+                part_path.display(),
+                expected_sha256,
+                actual
+            )));
+        }
+        return Ok(Some(actual));
+    }
+    Ok(sha256)
+}
+
+#[cfg(unix)]
+fn sync_parent_dir(file_path: &Path) -> common::error::Result<()> {
+    let dir = match file_path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+/// directories can't be opened for syncing on other platforms, the rename is left to the OS
+#[cfg(not(unix))]
+fn sync_parent_dir(_file_path: &Path) -> common::error::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use super::{finalize, part_path, record_version, recorded_version, version_key};
+    use crate::common::error::DownloadError;
+
+    #[test]
+    fn test_finalize() {
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path();
+        let target = dir.join("abc.txt");
+        let part = part_path(&target).unwrap();
+        assert_eq!(part, dir.join("abc.txt.part"));
+
+        fs::write(&part, b"abc").unwrap();
+        let result = finalize(&part, &target, Some(3), Some("0000"));
+        assert!(matches!(result, Err(DownloadError::ChecksumMismatch(_))));
+        assert!(!part.exists() && !target.exists());
+
+        fs::write(&part, b"abc").unwrap();
+        finalize(
+            &part,
+            &target,
+            Some(3),
+            Some("BA7816BF8F01CFEA414140DE5DAE2223B00361A396177A9CB410FF61F20015AD"),
+        )
+        .unwrap();
+        assert!(!part.exists());
+        assert_eq!(fs::read(&target).unwrap(), b"abc");
+    }
+
+    #[test]
+    fn test_record_version() {
+        let temp = tempfile::tempdir().unwrap();
+        let path = temp.path().join(".abc.txt.etag");
+        assert_eq!(recorded_version(&path), None);
+        record_version(&path, Some(version_key("\"e1\"", Some(&(0..8))))).unwrap();
+        assert_eq!(
+            recorded_version(&path).as_deref(),
+            Some("\"e1\"\nrange=0-8")
+        );
+        record_version(&path, None).unwrap();
+        assert_eq!(recorded_version(&path), None);
+        // nothing to forget
+        record_version(&path, None).unwrap();
+    }
+}
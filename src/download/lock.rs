@@ -0,0 +1,112 @@
+use std::fs::{self, File, OpenOptions};
+use std::path::{Path, PathBuf};
+
+use fs2::FileExt;
+
+use crate::common;
+
+/// Advisory lock held on a lock file for as long as the value lives.
+/// On unix the lock file is removed before the lock is released, elsewhere right after.
+pub(crate) struct PathLock {
+    path: PathBuf,
+    // released when the handle is closed
+    file: Option<File>,
+}
+
+impl PathLock {
+    /// block until the lock is ours
+    pub fn acquire(path: &Path) -> common::error::Result<PathLock> {
+        loop {
+            let file = Self::open(path)?;
+            file.lock_exclusive()?;
+            if let Some(lock) = Self::confirm(path, file)? {
+                return Ok(lock);
+            }
+        }
+    }
+
+    /// `None` when another process holds the lock
+    pub fn try_acquire(path: &Path) -> common::error::Result<Option<PathLock>> {
+        loop {
+            let file = Self::open(path)?;
+            match file.try_lock_exclusive() {
+                Ok(()) => (),
+                Err(e) if e.kind() == fs2::lock_contended_error().kind() => return Ok(None),
+                Err(e) => return Err(e.into()),
+            }
+            if let Some(lock) = Self::confirm(path, file)? {
+                return Ok(Some(lock));
+            }
+        }
+    }
+
+    fn open(path: &Path) -> common::error::Result<File> {
+        Ok(OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?)
+    }
+
+    /// the previous holder removes the lock file before unlocking it, so a lock taken on an
+    /// unlinked file is worthless and has to be taken again on the current one
+    fn confirm(path: &Path, file: File) -> common::error::Result<Option<PathLock>> {
+        if !is_same_file(path, &file)? {
+            return Ok(None);
+        }
+        Ok(Some(PathLock {
+            path: path.to_path_buf(),
+            file: Some(file),
+        }))
+    }
+}
+
+impl Drop for PathLock {
+    fn drop(&mut self) {
+        if cfg!(unix) {
+            // unlinked while still locked, a waiter sees that its lock is on a removed file
+            let _ = fs::remove_file(&self.path);
+            self.file.take();
+        } else {
+            // an open file can't be removed, and a waiter is never handed an unlinked one
+            self.file.take();
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+#[cfg(unix)]
+fn is_same_file(path: &Path, file: &File) -> common::error::Result<bool> {
+    use std::os::unix::fs::MetadataExt;
+
+    let opened = file.metadata()?;
+    match fs::metadata(path) {
+        Ok(current) => Ok(current.dev() == opened.dev() && current.ino() == opened.ino()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// open files can't be removed on other platforms, so the path always names the locked file
+#[cfg(not(unix))]
+fn is_same_file(_path: &Path, _file: &File) -> common::error::Result<bool> {
+    Ok(true)
+}
+
+#[cfg(test)]
+mod test {
+    use super::PathLock;
+
+    #[test]
+    fn test_path_lock() {
+        let temp = tempfile::tempdir().unwrap();
+        let path = temp.path().join("model.bin.lock");
+        let lock = PathLock::try_acquire(&path).unwrap().unwrap();
+        assert!(PathLock::try_acquire(&path).unwrap().is_none());
+        // released and removed together
+        drop(lock);
+        assert!(!path.exists());
+        assert!(PathLock::try_acquire(&path).unwrap().is_some());
+    }
+}
@@ -2,70 +2,77 @@ extern crate indicatif;
 
 use std::{
     collections::HashMap,
-    sync::{Arc, RwLock},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        mpsc, Arc, RwLock,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
 };
 
+/// how often the aggregator brings the bar up to date
+const REPORT_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Segment positions are plain atomics, counting a chunk doesn't lock anything. With a bar
+/// the totals are summed up by an aggregator thread every [`REPORT_INTERVAL`] instead of on
+/// every chunk, and once more when the progress is dropped.
 pub struct Progress {
-    multi_progress: Vec<Arc<RwLock<ProgressBar>>>,
-    progress_bars: HashMap<usize, Arc<RwLock<ProgressBar>>>,
-    inner_progress: Option<Arc<RwLock<self::indicatif::ProgressBar>>>,
+    segments: Arc<RwLock<Vec<Arc<ProgressBar>>>>,
+    progress_bars: HashMap<usize, Arc<ProgressBar>>,
+    aggregator: Option<Aggregator>,
 }
 
-#[warn(dead_code)]
 struct ProgressBar {
-    position: u64,
-    is_finished: bool,
+    position: AtomicU64,
+    is_finished: AtomicBool,
     total_len: u64,
 }
 
+/// stops once its sender is dropped
+struct Aggregator {
+    stop: mpsc::Sender<()>,
+    handle: JoinHandle<()>,
+}
+
 impl Default for Progress {
     fn default() -> Progress {
-        Progress {
-            multi_progress: Vec::new(),
-            progress_bars: HashMap::new(),
-            inner_progress: None,
-        }
+        Progress::with_bar(None)
     }
 }
 
 impl Progress {
-    fn reset_inner(&self) {
-        if let Some(pb) = self.inner_progress.as_ref() {
-            let mut total = 0;
-            let mut total_len = 0;
-            let mut all_finished = true;
-            for sub in self.multi_progress.iter() {
-                let pbr = sub.read().unwrap();
-                total += pbr.position;
-                all_finished &= pbr.is_finished;
-                total_len += pbr.total_len;
-            }
-            let pbw = pb.write().unwrap();
-            pbw.set_position(total);
-            pbw.set_length(total_len);
-            if all_finished {
-                pbw.finish();
-            }
-        }
-    }
-
     pub fn with_bar(bar: Option<self::indicatif::ProgressBar>) -> Progress {
+        let segments = Arc::new(RwLock::new(Vec::new()));
+        let aggregator = bar.map(|bar| {
+            let (stop, stopped) = mpsc::channel();
+            let segments = segments.clone();
+            let handle = thread::Builder::new()
+                .name("pget-progress".to_string())
+                .spawn(move || {
+                    while let Err(mpsc::RecvTimeoutError::Timeout) =
+                        stopped.recv_timeout(REPORT_INTERVAL)
+                    {
+                        report(&segments, &bar);
+                    }
+                    report(&segments, &bar);
+                })
+                .expect("failed to spawn the progress thread");
+            Aggregator { stop, handle }
+        });
         Progress {
-            multi_progress: Vec::new(),
+            segments,
             progress_bars: HashMap::new(),
-            inner_progress: match bar {
-                Some(inner) => Some(Arc::new(RwLock::new(inner))),
-                None => None,
-            },
+            aggregator,
         }
     }
+
     pub fn add(&mut self, range: usize, thread_number: usize) {
-        let progress_bar = Arc::new(RwLock::new(ProgressBar {
-            position: 0,
-            is_finished: false,
+        let progress_bar = Arc::new(ProgressBar {
+            position: AtomicU64::new(0),
+            is_finished: AtomicBool::new(false),
             total_len: range as u64,
-        }));
-        self.multi_progress.push(progress_bar.clone());
+        });
+        self.segments.write().unwrap().push(progress_bar.clone());
         self.progress_bars.insert(thread_number, progress_bar);
     }
 
@@ -74,7 +81,10 @@ impl Progress {
         for (k, v) in self.progress_bars.iter() {
             result.insert(
                 k,
-                (v.read().unwrap().position, v.read().unwrap().is_finished),
+                (
+                    v.position.load(Ordering::Relaxed),
+                    v.is_finished.load(Ordering::Acquire),
+                ),
             );
         }
         result
@@ -85,11 +95,7 @@ impl Progress {
             Some(x) => x,
             None => return,
         };
-        pb.write().unwrap().position += amount as u64;
-        // if let Some(pb) = self.inner_progress.as_ref() {
-        //     pb.write().unwrap().inc(amount as u64);
-        // }
-        self.reset_inner();
+        pb.position.fetch_add(amount as u64, Ordering::Relaxed);
     }
 
     pub fn set_position(&mut self, amount: u64, thread_number: usize) {
@@ -97,13 +103,12 @@ impl Progress {
             Some(x) => x,
             None => return,
         };
-        pb.write().unwrap().position = amount;
-        self.reset_inner();
+        pb.position.store(amount, Ordering::Relaxed);
     }
 
     pub fn is_finished(&self, thread_number: usize) -> bool {
         match self.progress_bars.get(&thread_number) {
-            Some(x) => x.read().unwrap().is_finished,
+            Some(x) => x.is_finished.load(Ordering::Acquire),
             None => false,
         }
     }
@@ -113,9 +118,62 @@ impl Progress {
             Some(x) => x,
             None => return,
         };
-        pb.write().unwrap().is_finished = true;
+        pb.is_finished.store(true, Ordering::Release);
+    }
+}
+
+impl Drop for Progress {
+    fn drop(&mut self) {
+        if let Some(Aggregator { stop, handle }) = self.aggregator.take() {
+            // the last report runs before the download returns
+            drop(stop);
+            let _ = handle.join();
+        }
+    }
+}
+
+/// sum up the segments into `bar`
+fn report(segments: &RwLock<Vec<Arc<ProgressBar>>>, bar: &self::indicatif::ProgressBar) {
+    let mut total = 0;
+    let mut total_len = 0;
+    let mut all_finished = true;
+    for sub in segments.read().unwrap().iter() {
+        total += sub.position.load(Ordering::Relaxed);
+        all_finished &= sub.is_finished.load(Ordering::Acquire);
+        total_len += sub.total_len;
+    }
+    bar.set_length(total_len);
+    bar.set_position(total);
+    if all_finished && !bar.is_finished() {
+        bar.finish();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Progress;
+
+    #[test]
+    fn test_progress() {
+        let bar = indicatif::ProgressBar::hidden();
+        let mut progress = Progress::with_bar(Some(bar.clone()));
+        progress.add(10, 1);
+        progress.add(5, 2);
+        progress.inc(4, 1);
+        progress.inc(6, 1);
+        progress.finish(1);
+        progress.set_position(3, 2);
+        let positions = progress.dump();
+        assert_eq!(positions[&1], (10, true));
+        assert_eq!(positions[&2], (3, false));
+        assert!(!progress.is_finished(2));
+
+        progress.inc(2, 2);
+        progress.finish(2);
+        // dropping it reports the final state
+        drop(progress);
+        assert_eq!(bar.length(), Some(15));
+        assert_eq!(bar.position(), 15);
+        assert!(bar.is_finished());
     }
-    // pub fn check_finish(&mut self) {
-    //     self.reset_inner();
-    // }
 }
@@ -1,25 +1,102 @@
 extern crate reqwest;
 
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
 use reqwest::header::HeaderMap;
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, instrument, Span};
+
+use crate::common::error::{DownloadError, Result as DownloadResult};
+use crate::common::options::DEFAULT_RETRIES;
+
+use super::throttle::{Throttle, ThrottlePermit};
 
 use self::reqwest::header;
 use self::reqwest::Client;
 use self::reqwest::Response;
 
 const CONTENT_RANGE: &str = "Content-Range";
+
+#[derive(Clone)]
 pub struct Network {
     pub client: Client,
+    /// shared by the clones, so every connection of a download backs off together
+    throttle: Arc<Throttle>,
 }
 
 impl Default for Network {
     fn default() -> Network {
         Network {
             client: Client::new(),
+            throttle: Arc::default(),
+        }
+    }
+}
+
+/// What a server tells about a file, learned from a one byte range request.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RemoteInfo {
+    /// total length, from `Content-Range` or, without range support, `Content-Length`
+    pub size: Option<u64>,
+    /// the server answered the probe with a proper `206` (or `416` for an empty file)
+    pub accepts_ranges: bool,
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+    pub content_type: Option<String>,
+    /// file name from `Content-Disposition`
+    pub filename: Option<String>,
+    /// url after redirects
+    pub final_url: String,
+    /// digests declared in `Digest`, `Repr-Digest`, `Content-Digest`, `Content-MD5`,
+    /// `x-goog-hash` and `x-amz-checksum-*`, by lowercase algorithm, values as sent
+    pub digests: BTreeMap<String, String>,
+    /// e.g. `HTTP/1.1`
+    pub http_version: String,
+}
+
+impl RemoteInfo {
+    /// the length segments can be planned with, only known when ranges work
+    pub fn range_length(&self) -> Option<u64> {
+        if self.accepts_ranges {
+            self.size
+        } else {
+            None
         }
     }
+
+    /// both describe the same version of the file, as far as the validators tell
+    pub fn same_version(&self, other: &RemoteInfo) -> bool {
+        if self.size != other.size {
+            return false;
+        }
+        if let (Some(etag), Some(other_etag)) = (&self.etag, &other.etag) {
+            return strip_weak(etag) == strip_weak(other_etag);
+        }
+        if let (Some(last_modified), Some(other_last_modified)) =
+            (&self.last_modified, &other.last_modified)
+        {
+            return last_modified == other_last_modified;
+        }
+        true
+    }
+}
+
+fn strip_weak(etag: &str) -> &str {
+    etag.strip_prefix("W/").unwrap_or(etag)
+}
+
+/// parsed `Content-Range: bytes <first>-<last>/<total>` header, both parts may be `*`
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct ContentRange {
+    pub range: Option<(u64, u64)>,
+    pub total: Option<u64>,
 }
 
 impl Network {
+    #[instrument(level = "debug", skip(self), fields(status))]
     pub async fn make_request(
         &self,
         url: &String,
@@ -31,27 +108,384 @@ impl Network {
             self.client.get(url)
         };
 
-        return request.send().await;
+        let resp = request.send().await?;
+        Span::current().record("status", resp.status().as_u16());
+        debug!(length = resp.content_length(), "response received");
+        Ok(resp)
     }
 
-    pub async fn get_content_length(&self, url: &String) -> Result<Option<u64>, reqwest::Error> {
-        let res = self
-            .make_request(url, Some("bytes=0-0".to_string()))
-            .await?;
+    /// request `[range_start, range_end)` and make sure the server answered with exactly that range,
+    /// so a `200` full body or a shifted range never ends up in a segment cache file
+    #[instrument(level = "debug", skip(self))]
+    pub async fn request_range(
+        &self,
+        url: &String,
+        range_start: u64,
+        range_end: u64,
+    ) -> DownloadResult<Response> {
+        let range = format!("bytes={}-{}", range_start, range_end - 1);
+        let resp = self.make_request(url, Some(range)).await?;
+        let resp = self.error_for_status(resp)?;
+        if resp.status() != StatusCode::PARTIAL_CONTENT {
+            return Err(DownloadError::unexpected_range(&format!(
+                "expect 206 for range {}-{}, got {}",
+                range_start,
+                range_end - 1,
+                resp.status()
+            )));
+        }
+        let content_range = resp
+            .headers()
+            .get(CONTENT_RANGE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(parse_content_range);
+        match content_range {
+            Some(ContentRange {
+                range: Some((first, last)),
+                ..
+            }) if first == range_start && last == range_end - 1 => Ok(resp),
+            other => Err(DownloadError::unexpected_range(&format!(
+                "expect range {}-{}, got {:?}",
+                range_start,
+                range_end - 1,
+                other
+            ))),
+        }
+    }
+
+    /// probe the file with a one byte range request.
+    /// an empty file can't satisfy `bytes=0-0`, so a `416` carrying `bytes */<total>` is accepted too.
+    /// a rate limited probe is repeated after the server's `Retry-After`
+    #[instrument(skip(self))]
+    pub async fn probe(&self, url: &String) -> DownloadResult<RemoteInfo> {
+        let mut attempt = 0;
+        let res = loop {
+            let _permit = self.connection().await;
+            let res = self
+                .make_request(url, Some("bytes=0-0".to_string()))
+                .await?;
+            if res.status() == StatusCode::RANGE_NOT_SATISFIABLE {
+                break res;
+            }
+            match self.error_for_status(res) {
+                Ok(res) => break res,
+                Err(DownloadError::RateLimited(_)) if attempt < DEFAULT_RETRIES => attempt += 1,
+                Err(e) => return Err(e),
+            }
+        };
         let headers = res.headers();
-        let content_range = get_length_from_meta(headers);
-        Ok(content_range)
+        let range_total = get_length_from_meta(headers);
+        let accepts_ranges = range_total.is_some()
+            && matches!(
+                res.status(),
+                StatusCode::PARTIAL_CONTENT | StatusCode::RANGE_NOT_SATISFIABLE
+            );
+        let size = if accepts_ranges {
+            range_total
+        } else {
+            res.content_length()
+        };
+        debug!(
+            status = res.status().as_u16(),
+            size, accepts_ranges, "probed"
+        );
+        Ok(RemoteInfo {
+            size,
+            accepts_ranges,
+            etag: header_string(headers, header::ETAG),
+            last_modified: header_string(headers, header::LAST_MODIFIED),
+            content_type: header_string(headers, header::CONTENT_TYPE),
+            filename: header_string(headers, header::CONTENT_DISPOSITION)
+                .and_then(|value| parse_content_disposition(&value)),
+            final_url: res.url().to_string(),
+            digests: parse_digests(headers),
+            http_version: format!("{:?}", res.version()),
+        })
+    }
+
+    /// the total length when the server does ranges, see [`Network::probe`]
+    pub async fn get_content_length(&self, url: &String) -> DownloadResult<Option<u64>> {
+        Ok(self.probe(url).await?.range_length())
+    }
+
+    /// like [`Response::error_for_status`], but a `429` or `503` pauses the connections for the
+    /// server's `Retry-After` and becomes a [`DownloadError::RateLimited`]
+    pub fn error_for_status(&self, resp: Response) -> DownloadResult<Response> {
+        let status = resp.status();
+        if status != StatusCode::TOO_MANY_REQUESTS && status != StatusCode::SERVICE_UNAVAILABLE {
+            return Ok(resp.error_for_status()?);
+        }
+        let retry_after = header_string(resp.headers(), header::RETRY_AFTER)
+            .and_then(|value| parse_retry_after(&value, SystemTime::now()));
+        let pause = self.throttle.rate_limited(retry_after);
+        Err(DownloadError::rate_limited(&format!(
+            "{} from {}, pausing for {:?}",
+            status,
+            resp.url(),
+            pause
+        )))
+    }
+
+    /// wait until a rate limit allows another connection, hold the permit while it is open
+    pub async fn connection(&self) -> ThrottlePermit {
+        self.throttle.acquire().await
+    }
+
+    /// what is left of the pause after a rate limit, zero when there is none
+    pub fn rate_limit_pause(&self) -> Duration {
+        self.throttle.remaining()
+    }
+
+    /// the connection limit shared by the clones of this network
+    pub(crate) fn throttle(&self) -> &Throttle {
+        &self.throttle
     }
 }
 
+/// the server refused the request, e.g. because a presigned url expired
+pub(crate) fn is_refused(error: &DownloadError) -> bool {
+    matches!(
+        error,
+        DownloadError::RequestError(e)
+            if matches!(e.status(), Some(StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN))
+    )
+}
+
+/// the length of a file that is read in byte ranges, an error when the server doesn't report it
+pub(crate) fn ranged_length(range_length: Option<u64>) -> DownloadResult<u64> {
+    range_length.ok_or(DownloadError::parameter(
+        "the server doesn't report the file length, byte ranges are not available",
+    ))
+}
+
+/// `Retry-After` in delta-seconds or as an HTTP-date, a date in the past means right away
+pub(crate) fn parse_retry_after(value: &str, now: SystemTime) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = httpdate::parse_http_date(value).ok()?;
+    Some(date.duration_since(now).unwrap_or(Duration::ZERO))
+}
+
 fn get_length_from_meta(headers: &HeaderMap) -> Option<u64> {
     let content_range = headers.get(CONTENT_RANGE)?;
-    let size = content_range
-        .to_str()
-        .ok()?
-        .split('/')
-        .last()?
-        .parse::<u64>()
-        .ok()?;
-    Some(size)
+    parse_content_range(content_range.to_str().ok()?)?.total
+}
+
+fn header_string(headers: &HeaderMap, name: header::HeaderName) -> Option<String> {
+    Some(headers.get(name)?.to_str().ok()?.trim().to_string())
+}
+
+/// `filename*=UTF-8''<percent encoded>` wins over a plain `filename=`
+pub(crate) fn parse_content_disposition(value: &str) -> Option<String> {
+    let mut plain = None;
+    for param in value.split(';').skip(1) {
+        let Some((key, value)) = param.split_once('=') else {
+            continue;
+        };
+        let value = value.trim();
+        match key.trim().to_ascii_lowercase().as_str() {
+            "filename*" => {
+                let (charset, rest) = value.split_once('\'')?;
+                let (_language, encoded) = rest.split_once('\'')?;
+                if charset.eq_ignore_ascii_case("utf-8") {
+                    if let Some(name) = percent_decode(encoded) {
+                        return Some(name);
+                    }
+                }
+            }
+            "filename" => plain = Some(value.trim_matches('"').to_string()),
+            _ => (),
+        }
+    }
+    plain.filter(|name| !name.is_empty())
+}
+
+fn percent_decode(value: &str) -> Option<String> {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = std::str::from_utf8(bytes.get(i + 1..i + 3)?).ok()?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+pub(crate) fn parse_digests(headers: &HeaderMap) -> BTreeMap<String, String> {
+    let mut digests = BTreeMap::new();
+    // `Digest: sha-256=<b64>, md5=<b64>` and the structured `Repr-Digest: sha-256=:<b64>:`
+    for name in ["digest", "repr-digest", "content-digest", "x-goog-hash"] {
+        for value in headers.get_all(name) {
+            let Ok(value) = value.to_str() else {
+                continue;
+            };
+            for item in value.split(',') {
+                if let Some((algorithm, digest)) = item.split_once('=') {
+                    digests.insert(
+                        algorithm.trim().to_ascii_lowercase(),
+                        digest.trim().trim_matches(':').to_string(),
+                    );
+                }
+            }
+        }
+    }
+    if let Some(md5) = header_string(headers, header::HeaderName::from_static("content-md5")) {
+        digests.insert("md5".to_string(), md5);
+    }
+    for (name, value) in headers {
+        if let Some(algorithm) = name.as_str().strip_prefix("x-amz-checksum-") {
+            if let Ok(value) = value.to_str() {
+                digests.insert(algorithm.to_string(), value.trim().to_string());
+            }
+        }
+    }
+    digests
+}
+
+pub(crate) fn parse_content_range(value: &str) -> Option<ContentRange> {
+    let (unit, rest) = value.trim().split_once(' ')?;
+    if !unit.eq_ignore_ascii_case("bytes") {
+        return None;
+    }
+    let (range, total) = rest.trim().split_once('/')?;
+    let total = match total.trim() {
+        "*" => None,
+        total => Some(total.parse::<u64>().ok()?),
+    };
+    let range = match range.trim() {
+        "*" => None,
+        range => {
+            let (first, last) = range.split_once('-')?;
+            let first = first.trim().parse::<u64>().ok()?;
+            let last = last.trim().parse::<u64>().ok()?;
+            if last < first {
+                return None;
+            }
+            Some((first, last))
+        }
+    };
+    Some(ContentRange { range, total })
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    use reqwest::header::{HeaderMap, HeaderValue};
+
+    use super::{
+        parse_content_disposition, parse_content_range, parse_digests, parse_retry_after,
+        ContentRange, RemoteInfo,
+    };
+
+    #[test]
+    fn test_parse_content_range() {
+        assert_eq!(
+            parse_content_range("bytes 0-0/1234"),
+            Some(ContentRange {
+                range: Some((0, 0)),
+                total: Some(1234)
+            })
+        );
+        assert_eq!(
+            parse_content_range("bytes 100-199/*"),
+            Some(ContentRange {
+                range: Some((100, 199)),
+                total: None
+            })
+        );
+        assert_eq!(
+            parse_content_range("bytes */0"),
+            Some(ContentRange {
+                range: None,
+                total: Some(0)
+            })
+        );
+        assert_eq!(parse_content_range("bytes 20-10/100"), None);
+        assert_eq!(parse_content_range("items 0-1/2"), None);
+        assert_eq!(parse_content_range("garbage"), None);
+    }
+
+    #[test]
+    fn test_parse_content_disposition() {
+        assert_eq!(
+            parse_content_disposition("attachment; filename=\"model.bin\""),
+            Some("model.bin".to_string())
+        );
+        assert_eq!(
+            parse_content_disposition(
+                "attachment; filename=\"fallback.txt\"; filename*=UTF-8''na%C3%AFve%20file.txt"
+            ),
+            Some("naïve file.txt".to_string())
+        );
+        assert_eq!(parse_content_disposition("inline"), None);
+    }
+
+    #[test]
+    fn test_parse_digests() {
+        let mut headers = HeaderMap::new();
+        headers.insert("digest", HeaderValue::from_static("SHA-256=abc=, md5=def"));
+        headers.insert("repr-digest", HeaderValue::from_static("sha-512=:xyz:"));
+        headers.insert("x-amz-checksum-crc32c", HeaderValue::from_static("AAAA"));
+        let digests = parse_digests(&headers);
+        assert_eq!(digests.get("sha-256").map(String::as_str), Some("abc="));
+        assert_eq!(digests.get("md5").map(String::as_str), Some("def"));
+        assert_eq!(digests.get("sha-512").map(String::as_str), Some("xyz"));
+        assert_eq!(digests.get("crc32c").map(String::as_str), Some("AAAA"));
+    }
+
+    #[test]
+    fn test_same_version() {
+        let info = RemoteInfo {
+            size: Some(10),
+            etag: Some("W/\"a\"".to_string()),
+            last_modified: Some("yesterday".to_string()),
+            ..Default::default()
+        };
+        let mut other = info.clone();
+        other.etag = Some("\"a\"".to_string());
+        other.last_modified = Some("today".to_string());
+        assert!(info.same_version(&other));
+        other.etag = Some("\"b\"".to_string());
+        assert!(!info.same_version(&other));
+        other.etag = None;
+        other.size = Some(11);
+        assert!(!info.same_version(&other));
+    }
+
+    #[test]
+    fn test_parse_retry_after() {
+        // Sun, 06 Nov 1994 08:49:37 GMT
+        let now = UNIX_EPOCH + Duration::from_secs(784111777);
+        assert_eq!(
+            parse_retry_after(" 120 ", now),
+            Some(Duration::from_secs(120))
+        );
+        assert_eq!(
+            parse_retry_after("Sun, 06 Nov 1994 08:50:07 GMT", now),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(
+            parse_retry_after("Sunday, 06-Nov-94 08:49:47 GMT", now),
+            Some(Duration::from_secs(10))
+        );
+        assert_eq!(
+            parse_retry_after("Sun Nov  6 08:49:37 1994", now),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(
+            parse_retry_after("Sun, 06 Nov 1994 08:00:00 GMT", now),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(parse_retry_after("soon", SystemTime::now()), None);
+        assert_eq!(parse_retry_after("-1", SystemTime::now()), None);
+    }
 }
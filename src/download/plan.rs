@@ -0,0 +1,305 @@
+use std::fs;
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+
+use super::network::RemoteInfo;
+use super::{free_file_name, output_bounds, Download, CACHE_REMOTE_FILE};
+use crate::common;
+use crate::common::error::DownloadError;
+use crate::common::options::ExistingFilePolicy;
+
+/// What a download would do, worked out from a probe and the cache without transferring the
+/// file or writing anything.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DownloadPlan {
+    pub url: String,
+    /// the file that would be written, after [`ExistingFilePolicy::Rename`]
+    pub output_file: PathBuf,
+    pub remote: RemoteInfo,
+    pub strategy: PlanStrategy,
+    /// bytes the output file ends up with, unknown when the server doesn't tell
+    pub total_bytes: Option<u64>,
+    /// bytes taken from an existing target file (see [`ExistingFilePolicy::AdoptPrefix`])
+    pub adopted_bytes: u64,
+    /// bytes already in the segment cache
+    pub cached_bytes: u64,
+    /// bytes still to be transferred
+    pub remaining_bytes: Option<u64>,
+    pub cache_dir: Option<PathBuf>,
+    /// the cache belongs to another version of the remote file and would be dropped
+    pub stale_cache: bool,
+    pub segments: Vec<PlannedSegment>,
+    /// worst-case bytes written below the cache dir
+    pub cache_space_required: u64,
+    /// worst-case bytes written beside the output file
+    pub target_space_required: u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PlanStrategy {
+    /// the target file is there and matches, nothing would be transferred
+    Skip,
+    /// one plain request without a cache, for small or empty files and servers without ranges
+    SingleRequest,
+    /// concurrent range requests through the segment cache
+    Segments,
+}
+
+/// one range request of a [`DownloadPlan`], `[start, end)` in the remote file
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PlannedSegment {
+    pub thread_number: usize,
+    pub start: u64,
+    pub end: u64,
+    /// bytes of the segment already in the cache
+    pub cached_bytes: u64,
+}
+
+impl Download {
+    /// the same decisions as [`Download::get`], without locks, transfers or writes
+    pub fn plan(mut self) -> common::error::Result<DownloadPlan> {
+        if self.filename.exists() {
+            match self.options.existing_file {
+                ExistingFilePolicy::Fail => {
+                    return Err(DownloadError::already_exists(&format!(
+                        "{} exists already",
+                        self.filename.display()
+                    )))
+                }
+                ExistingFilePolicy::Rename => self.filename = free_file_name(&self.filename),
+                _ => (),
+            }
+        }
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        let remote = rt.block_on(self.network.probe(&self.url))?;
+        let content_length = remote.range_length();
+        self.remote_info = Some(remote.clone());
+
+        let (output_start, output_end) = output_bounds(self.range.as_ref(), content_length)?;
+        let total_bytes = output_end.map(|end| end - output_start).or(remote.size);
+
+        let mut plan = DownloadPlan {
+            url: self.url.clone(),
+            output_file: self.filename.clone(),
+            remote,
+            strategy: PlanStrategy::SingleRequest,
+            total_bytes,
+            adopted_bytes: 0,
+            cached_bytes: 0,
+            remaining_bytes: total_bytes,
+            cache_dir: None,
+            stale_cache: false,
+            segments: vec![],
+            cache_space_required: 0,
+            target_space_required: total_bytes.unwrap_or(0),
+        };
+        let Some(output_end) = output_end else {
+            return Ok(plan);
+        };
+        let length = output_end - output_start;
+
+        // only an existing file that matches, or is adopted as a whole, makes the transfer moot
+        let mut skip = false;
+        if let Ok(metadata) = fs::metadata(&self.filename) {
+            match self.options.existing_file {
+                ExistingFilePolicy::SkipIfMatch if metadata.len() == length => {
+                    skip = self.matches_existing(&self.filename)?;
+                }
+                // a range only adopts a shorter file, a whole file also a complete one
+                ExistingFilePolicy::AdoptPrefix
+                    if metadata.len() < length
+                        || (self.range.is_none() && metadata.len() == length) =>
+                {
+                    plan.adopted_bytes = metadata.len();
+                    skip = plan.adopted_bytes == length;
+                }
+                _ => (),
+            }
+        }
+        if skip {
+            plan.strategy = PlanStrategy::Skip;
+            plan.remaining_bytes = Some(0);
+            plan.target_space_required = 0;
+            return Ok(plan);
+        }
+        if length as usize <= self.options.small_file_threshold {
+            // small files are fetched whole, an adopted prefix is not used
+            plan.adopted_bytes = 0;
+            plan.remaining_bytes = Some(length);
+            return Ok(plan);
+        }
+
+        let start = (output_start + plan.adopted_bytes) as usize;
+        let cache_dir = self.cache_dir_from(start)?;
+        let file_name = self
+            .filename
+            .file_name()
+            .ok_or(DownloadError::parameter(
+                "target file should not be a director",
+            ))?
+            .to_string_lossy()
+            .into_owned();
+        let cached_remote = fs::read_to_string(cache_dir.join(CACHE_REMOTE_FILE))
+            .ok()
+            .and_then(|content| serde_json::from_str::<RemoteInfo>(&content).ok());
+        plan.stale_cache =
+            matches!(cached_remote, Some(cached) if !cached.same_version(&plan.remote));
+
+        let segments = Download::cached_segments(
+            self.segment_count(),
+            start,
+            output_end as usize,
+            &cache_dir,
+            &file_name,
+        );
+        plan.segments = segments
+            .into_iter()
+            .map(|segment| {
+                let cached_end = match segment.query_start {
+                    _ if plan.stale_cache => segment.range_start,
+                    Some(query_start) => query_start,
+                    None => segment.range_end,
+                };
+                PlannedSegment {
+                    thread_number: segment.thread_number,
+                    start: segment.range_start as u64,
+                    end: segment.range_end as u64,
+                    cached_bytes: (cached_end - segment.range_start) as u64,
+                }
+            })
+            .collect();
+        plan.strategy = PlanStrategy::Segments;
+        plan.cached_bytes = plan.segments.iter().map(|s| s.cached_bytes).sum();
+        plan.remaining_bytes = Some(length - plan.adopted_bytes - plan.cached_bytes);
+        plan.cache_space_required = plan.remaining_bytes.unwrap_or(0);
+        plan.cache_dir = Some(cache_dir);
+        Ok(plan)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::path::{Path, PathBuf};
+
+    use super::super::finalize;
+    use super::super::test_server::{Reply, TestServer};
+    use super::super::{segment_bounds, Download};
+    use super::PlanStrategy;
+    use crate::common::options::{CacheLocation, DownloadOptions, ExistingFilePolicy};
+
+    fn planned(
+        url: &str,
+        filename: PathBuf,
+        cache: &Path,
+        existing_file: ExistingFilePolicy,
+    ) -> Download {
+        Download {
+            url: url.to_string(),
+            filename,
+            options: DownloadOptions {
+                cache_location: CacheLocation::Dir(cache.to_path_buf()),
+                small_file_threshold: 1024,
+                existing_file,
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_plan() {
+        let data: Vec<u8> = (0..64 * 1024u32).map(|i| (i % 251) as u8).collect();
+        let length = data.len() as u64;
+        let served = data.clone();
+        let server = TestServer::start(move |request| {
+            Reply::file(&served, request).header("ETag", "\"v1\"")
+        });
+        let url = server.url("/data.bin");
+        let temp = tempfile::tempdir().unwrap();
+        let target = temp.path().join("data.bin");
+        let download = |policy| planned(&url, target.clone(), temp.path(), policy);
+
+        // nothing there yet
+        let plan = download(ExistingFilePolicy::Overwrite).plan().unwrap();
+        assert_eq!(plan.strategy, PlanStrategy::Segments);
+        assert_eq!(plan.total_bytes, Some(length));
+        assert_eq!((plan.adopted_bytes, plan.cached_bytes), (0, 0));
+        assert_eq!(plan.remaining_bytes, Some(length));
+        assert_eq!(plan.segments.len(), 4);
+        assert!(!plan.stale_cache);
+
+        // the first segment is in the cache
+        let resumed = download(ExistingFilePolicy::Overwrite);
+        let cache_dir = resumed.cache_dir_from(0).unwrap();
+        let (first_start, first_end) = segment_bounds(resumed.threads, 0, data.len())[0];
+        fs::create_dir_all(&cache_dir).unwrap();
+        fs::write(
+            Download::cache_file_name(&cache_dir, "data.bin", 1),
+            &data[first_start..first_end],
+        )
+        .unwrap();
+        let status = (1..=4)
+            .map(|thread| match thread {
+                1 => (thread, ((first_end - first_start) as u64, true)),
+                _ => (thread, (0, false)),
+            })
+            .collect();
+        Download::dump_process(status, cache_dir.clone()).unwrap();
+        let plan = resumed.plan().unwrap();
+        let cached = (first_end - first_start) as u64;
+        assert_eq!(plan.strategy, PlanStrategy::Segments);
+        assert_eq!(plan.cached_bytes, cached);
+        assert_eq!(plan.segments[0].cached_bytes, cached);
+        assert_eq!(plan.remaining_bytes, Some(length - cached));
+        assert_eq!(plan.cache_dir, Some(cache_dir.clone()));
+        fs::remove_dir_all(&cache_dir).unwrap();
+
+        // a half-finished file is continued after its last byte
+        fs::write(&target, &data[..5000]).unwrap();
+        let plan = download(ExistingFilePolicy::AdoptPrefix).plan().unwrap();
+        assert_eq!(plan.strategy, PlanStrategy::Segments);
+        assert_eq!(plan.adopted_bytes, 5000);
+        assert_eq!(plan.segments[0].start, 5000);
+        assert_eq!(plan.remaining_bytes, Some(length - 5000));
+
+        // a complete file is skipped once its ETag is known, or adopted whole
+        fs::write(&target, &data).unwrap();
+        let plan = download(ExistingFilePolicy::SkipIfMatch).plan().unwrap();
+        assert_eq!(plan.strategy, PlanStrategy::Segments);
+        let skipped = download(ExistingFilePolicy::SkipIfMatch);
+        finalize::record_version(&skipped.version_path().unwrap(), Some("\"v1\"".to_string()))
+            .unwrap();
+        let plan = skipped.plan().unwrap();
+        assert_eq!(plan.strategy, PlanStrategy::Skip);
+        assert_eq!(plan.remaining_bytes, Some(0));
+        let plan = download(ExistingFilePolicy::AdoptPrefix).plan().unwrap();
+        assert_eq!(plan.strategy, PlanStrategy::Skip);
+        assert_eq!(plan.adopted_bytes, length);
+    }
+
+    #[test]
+    fn test_plan_empty_file() {
+        let server = TestServer::start(|request| Reply::file(b"", request));
+        let url = server.url("/empty");
+        let temp = tempfile::tempdir().unwrap();
+        let target = temp.path().join("empty");
+
+        // nothing exists to skip, the empty file still has to be written
+        for policy in [
+            ExistingFilePolicy::SkipIfMatch,
+            ExistingFilePolicy::AdoptPrefix,
+        ] {
+            let plan = planned(&url, target.clone(), temp.path(), policy)
+                .plan()
+                .unwrap();
+            assert_eq!(plan.strategy, PlanStrategy::SingleRequest);
+            assert_eq!(plan.total_bytes, Some(0));
+            assert_eq!(plan.remaining_bytes, Some(0));
+        }
+        assert!(!target.exists());
+    }
+}
@@ -0,0 +1,106 @@
+use std::sync::Mutex;
+use std::time::Duration;
+
+use tracing::{info, instrument};
+
+use crate::common;
+use crate::common::error::DownloadError;
+use crate::common::options::UrlRefresh;
+
+use super::events::{DownloadEvent, Events};
+use super::network::{Network, RemoteInfo};
+
+/// The url the segments of one download request. Once the server refuses it with `401` or
+/// `403` it is swapped for a fresh one, all segments continue with that.
+pub(crate) struct SegmentUrl {
+    original: String,
+    current: Mutex<String>,
+    refresh: Option<UrlRefresh>,
+    /// what the download started with, a fresh url has to serve the same version
+    remote: Option<RemoteInfo>,
+    events: Events,
+    /// segments refused at the same time wait for one refresh instead of each asking
+    refreshing: tokio::sync::Mutex<()>,
+}
+
+impl SegmentUrl {
+    pub fn new(
+        url: String,
+        refresh: Option<UrlRefresh>,
+        remote: Option<RemoteInfo>,
+        events: Events,
+    ) -> SegmentUrl {
+        SegmentUrl {
+            original: url.clone(),
+            current: Mutex::new(url),
+            refresh,
+            remote,
+            events,
+            refreshing: tokio::sync::Mutex::new(()),
+        }
+    }
+
+    pub fn get(&self) -> String {
+        self.current.lock().unwrap().clone()
+    }
+
+    /// how long a segment waits after a refresh before its `attempt`. a fresh link is used right
+    /// away, the original url is only asked again after the same delay as other failures
+    pub fn retry_delay(&self, attempt: usize) -> Duration {
+        match self.refresh {
+            Some(_) => Duration::ZERO,
+            None => Duration::from_secs(attempt as u64),
+        }
+    }
+
+    /// replace the `refused` url, returns the url to continue with or `None` when the refresh
+    /// callback gave up. the new url is probed and has to point at the same version of the file
+    #[instrument(level = "debug", skip_all)]
+    pub async fn refresh(
+        &self,
+        network: &Network,
+        refused: &str,
+    ) -> common::error::Result<Option<String>> {
+        let _refreshing = self.refreshing.lock().await;
+        let current = self.get();
+        if current != refused {
+            // another segment refreshed it while this one waited
+            return Ok(Some(current));
+        }
+        let fresh = match &self.refresh {
+            Some(refresh) => {
+                // the callback may block, e.g. on its own request for a new link
+                let refresh = refresh.clone();
+                let refused = refused.to_owned();
+                let fresh = tokio::task::spawn_blocking(move || refresh.call(&refused))
+                    .await
+                    .map_err(|e| DownloadError::system(&e.to_string()))?;
+                match fresh {
+                    Some(fresh) => fresh,
+                    None => return Ok(None),
+                }
+            }
+            // probing the original url again resolves its redirects anew
+            None => self.original.clone(),
+        };
+        let remote = network.probe(&fresh).await?;
+        if let Some(expected) = &self.remote {
+            if !expected.same_version(&remote) {
+                return Err(DownloadError::remote_changed(&format!(
+                    "the refreshed url serves {:?} bytes with ETag {:?}, the download started \
+                     with {:?} bytes and ETag {:?}",
+                    remote.size, remote.etag, expected.size, expected.etag
+                )));
+            }
+        }
+        info!("url refreshed");
+        *self.current.lock().unwrap() = fresh.clone();
+        if fresh != refused {
+            self.events.emit(DownloadEvent::UrlRefreshed {
+                from: refused.to_owned(),
+                to: fresh.clone(),
+            });
+        }
+        Ok(Some(fresh))
+    }
+}
@@ -0,0 +1,734 @@
+use std::collections::{HashMap, VecDeque};
+use std::fs::{File, OpenOptions};
+use std::future::Future;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+use bytes::{Bytes, BytesMut};
+use futures::future::join_all;
+use tokio::io::{AsyncRead, AsyncSeek, ReadBuf};
+use tokio::runtime::Runtime;
+
+use super::events::Events;
+use super::lock::PathLock;
+use super::network::{self, Network, RemoteInfo};
+use super::refresh::SegmentUrl;
+use super::retry::Retries;
+use super::watchdog::Watchdog;
+use super::{progress, Download};
+use crate::common;
+use crate::common::error::DownloadError;
+use crate::common::options::{RemoteFileOptions, SpillOptions};
+
+type BlockFuture = Pin<Box<dyn Future<Output = common::error::Result<Bytes>> + Send>>;
+
+/// A remote file read through range requests, implementing [`Read`] and [`Seek`].
+///
+/// # Examples
+///
+/// ```no_run
+/// use std::io::{Read, Seek, SeekFrom};
+/// let mut file = pget::RemoteFile::open(
+///     "https://hf-mirror.com/Qwen/Qwen1.5-0.5B/resolve/main/model.safetensors",
+/// )
+/// .unwrap();
+/// let mut header_len = [0u8; 8];
+/// file.read_exact(&mut header_len).unwrap();
+/// file.seek(SeekFrom::End(-16)).unwrap();
+/// ```
+///
+/// It blocks on a runtime of its own, so it can't be used from within a tokio runtime: opening
+/// or reading it there returns an error, use [`AsyncRemoteFile`] instead.
+pub struct RemoteFile {
+    blocks: Arc<RemoteBlocks>,
+    position: u64,
+    runtime: Runtime,
+}
+
+/// A remote file read through range requests, implementing tokio's [`AsyncRead`] and [`AsyncSeek`].
+pub struct AsyncRemoteFile {
+    blocks: Arc<RemoteBlocks>,
+    position: u64,
+    pending: Option<(u64, BlockFuture)>,
+}
+
+impl RemoteFile {
+    pub fn open<P: AsRef<str>>(url: P) -> common::error::Result<RemoteFile> {
+        Self::open_with_options(url, RemoteFileOptions::default())
+    }
+
+    pub fn open_with_options<P: AsRef<str>>(
+        url: P,
+        options: RemoteFileOptions,
+    ) -> common::error::Result<RemoteFile> {
+        outside_runtime()?;
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        let blocks = runtime.block_on(RemoteBlocks::open(url.as_ref(), options))?;
+        Ok(RemoteFile {
+            blocks: Arc::new(blocks),
+            position: 0,
+            runtime,
+        })
+    }
+
+    /// total length of the remote file
+    pub fn len(&self) -> u64 {
+        self.blocks.length
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blocks.length == 0
+    }
+}
+
+impl AsyncRemoteFile {
+    pub async fn open<P: AsRef<str>>(url: P) -> common::error::Result<AsyncRemoteFile> {
+        Self::open_with_options(url, RemoteFileOptions::default()).await
+    }
+
+    pub async fn open_with_options<P: AsRef<str>>(
+        url: P,
+        options: RemoteFileOptions,
+    ) -> common::error::Result<AsyncRemoteFile> {
+        let blocks = RemoteBlocks::open(url.as_ref(), options).await?;
+        Ok(AsyncRemoteFile {
+            blocks: Arc::new(blocks),
+            position: 0,
+            pending: None,
+        })
+    }
+
+    /// total length of the remote file
+    pub fn len(&self) -> u64 {
+        self.blocks.length
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blocks.length == 0
+    }
+}
+
+/// blocking on a runtime of its own would panic inside another one
+fn outside_runtime() -> common::error::Result<()> {
+    if tokio::runtime::Handle::try_current().is_ok() {
+        return Err(DownloadError::parameter(
+            "RemoteFile blocks and can't be used within a tokio runtime, use AsyncRemoteFile",
+        ));
+    }
+    Ok(())
+}
+
+impl Read for RemoteFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() || self.position >= self.blocks.length {
+            return Ok(0);
+        }
+        outside_runtime()?;
+        let index = self.position / self.blocks.options.block_size;
+        let block = self.runtime.block_on(self.blocks.clone().block(index))?;
+        let count = self.blocks.copy_from_block(&block, self.position, buf);
+        self.position += count as u64;
+        Ok(count)
+    }
+}
+
+impl Seek for RemoteFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.position = seek_position(self.position, self.blocks.length, pos)?;
+        Ok(self.position)
+    }
+}
+
+impl AsyncRead for AsyncRemoteFile {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if buf.remaining() == 0 || this.position >= this.blocks.length {
+            return Poll::Ready(Ok(()));
+        }
+        let index = this.position / this.blocks.options.block_size;
+        if !matches!(&this.pending, Some((pending_index, _)) if *pending_index == index) {
+            this.pending = Some((index, Box::pin(this.blocks.clone().block(index))));
+        }
+        let (_, future) = this.pending.as_mut().unwrap();
+        match future.as_mut().poll(cx) {
+            Poll::Pending => Poll::Pending,
+            Poll::Ready(result) => {
+                this.pending = None;
+                let block = result?;
+                let count =
+                    this.blocks
+                        .copy_from_block(&block, this.position, buf.initialize_unfilled());
+                buf.advance(count);
+                this.position += count as u64;
+                Poll::Ready(Ok(()))
+            }
+        }
+    }
+}
+
+impl AsyncSeek for AsyncRemoteFile {
+    fn start_seek(self: Pin<&mut Self>, position: SeekFrom) -> io::Result<()> {
+        let this = self.get_mut();
+        this.position = seek_position(this.position, this.blocks.length, position)?;
+        Ok(())
+    }
+
+    fn poll_complete(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
+        Poll::Ready(Ok(self.position))
+    }
+}
+
+fn seek_position(current: u64, length: u64, pos: SeekFrom) -> io::Result<u64> {
+    let (base, offset) = match pos {
+        SeekFrom::Start(offset) => return Ok(offset),
+        SeekFrom::End(offset) => (length, offset),
+        SeekFrom::Current(offset) => (current, offset),
+    };
+    base.checked_add_signed(offset).ok_or(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "invalid seek to a negative or overflowing position",
+    ))
+}
+
+/// blocks of the remote file shared by the readers, with a small LRU cache
+struct RemoteBlocks {
+    network: Network,
+    url: SegmentUrl,
+    length: u64,
+    options: RemoteFileOptions,
+    cache: Mutex<BlockCache>,
+    spill: Option<Mutex<Spill>>,
+}
+
+#[derive(Default)]
+struct BlockCache {
+    blocks: HashMap<u64, Bytes>,
+    order: VecDeque<u64>,
+    last_index: Option<u64>,
+}
+
+impl RemoteBlocks {
+    async fn open(url: &str, options: RemoteFileOptions) -> common::error::Result<RemoteBlocks> {
+        if options.block_size == 0 {
+            return Err(DownloadError::parameter("block size should not be 0"));
+        }
+        let network = Network::default();
+        let url = url.to_owned();
+        let remote_info = network.probe(&url).await?;
+        let length = network::ranged_length(remote_info.range_length())?;
+        let spill = match options.spill.clone() {
+            Some(spill_options) => {
+                let (url, remote_info) = (url.clone(), remote_info.clone());
+                tokio::task::spawn_blocking(move || Spill::open(&url, &remote_info, &spill_options))
+                    .await
+                    .map_err(|e| DownloadError::system(&e.to_string()))??
+                    .map(Mutex::new)
+            }
+            None => None,
+        };
+        let url = SegmentUrl::new(
+            url,
+            options.refresh_url.clone(),
+            Some(remote_info),
+            Events::new(None),
+        );
+        Ok(RemoteBlocks {
+            network,
+            url,
+            length,
+            options,
+            cache: Mutex::new(BlockCache::default()),
+            spill,
+        })
+    }
+
+    fn block_count(&self) -> u64 {
+        self.length.div_ceil(self.options.block_size)
+    }
+
+    /// copy the bytes from `position` on out of `block`, which has to hold `position`
+    fn copy_from_block(&self, block: &Bytes, position: u64, buf: &mut [u8]) -> usize {
+        let offset = (position % self.options.block_size) as usize;
+        let count = buf.len().min(block.len() - offset);
+        buf[..count].copy_from_slice(&block[offset..offset + count]);
+        count
+    }
+
+    /// block `index`, fetched together with the read-ahead window when it is not cached
+    async fn block(self: Arc<Self>, index: u64) -> common::error::Result<Bytes> {
+        let (indexes, cached) = {
+            let mut cache = self.cache.lock().unwrap();
+            let sequential = index == 0 || cache.last_index == Some(index - 1);
+            cache.last_index = Some(index);
+            if let Some(block) = cache.get(index) {
+                (vec![], Some(block))
+            } else {
+                let read_ahead = if sequential {
+                    self.options.read_ahead as u64
+                } else {
+                    0
+                };
+                let last = (index + read_ahead).min(self.block_count().saturating_sub(1));
+                let indexes = (index..=last)
+                    .filter(|i| *i == index || !cache.blocks.contains_key(i))
+                    .collect::<Vec<_>>();
+                (indexes, None)
+            }
+        };
+        if let Some(block) = cached {
+            self.spill(vec![(index, block.clone())]).await?;
+            return Ok(block);
+        }
+
+        let fetched = join_all(indexes.iter().map(|i| self.fetch(*i))).await;
+        let mut blocks = Vec::with_capacity(indexes.len());
+        for (i, result) in indexes.into_iter().zip(fetched) {
+            match result {
+                Ok(block) => blocks.push((i, block)),
+                // a failed read-ahead block is simply fetched again when it is read
+                Err(e) if i == index => return Err(e),
+                Err(_) => (),
+            }
+        }
+        {
+            let mut cache = self.cache.lock().unwrap();
+            for (i, block) in &blocks {
+                cache.insert(*i, block.clone(), self.options.cache_blocks);
+            }
+        }
+        let block = blocks
+            .iter()
+            .find(|(i, _)| *i == index)
+            .map(|(_, block)| block.clone())
+            .ok_or(DownloadError::system("block was not fetched"))?;
+        self.spill(blocks).await?;
+        Ok(block)
+    }
+
+    /// hand blocks that were read to the spill, blocks are passed whenever they are served
+    /// so the ones skipped while the segment prefix had a gap get another chance. the files
+    /// are written on the blocking pool, blocks the spill has no use for don't get that far
+    async fn spill(self: &Arc<Self>, blocks: Vec<(u64, Bytes)>) -> common::error::Result<()> {
+        let Some(spill) = &self.spill else {
+            return Ok(());
+        };
+        let block_size = self.options.block_size;
+        let wanted = {
+            let spill = spill.lock().unwrap();
+            blocks
+                .into_iter()
+                .filter(|(index, block)| spill.wants(index * block_size, block.len() as u64))
+                .collect::<Vec<_>>()
+        };
+        if wanted.is_empty() {
+            return Ok(());
+        }
+        let this = self.clone();
+        tokio::task::spawn_blocking(move || {
+            let Some(spill) = &this.spill else {
+                return Ok(());
+            };
+            let mut spill = spill.lock().unwrap();
+            for (index, block) in wanted {
+                spill.write(index * block_size, &block)?;
+            }
+            Ok(())
+        })
+        .await
+        .map_err(|e| DownloadError::system(&e.to_string()))?
+    }
+
+    async fn fetch(&self, index: u64) -> common::error::Result<Bytes> {
+        let start = index * self.options.block_size;
+        let end = (start + self.options.block_size).min(self.length);
+        let mut retry = Retries::new(&self.network, &self.url, self.options.retries);
+        loop {
+            let url = self.url.get();
+            let permit = self.network.connection().await;
+            let result = self.fetch_once(&url, start, end).await;
+            drop(permit);
+            match result {
+                Ok(block) => return Ok(block),
+                Err(e) => retry.schedule(e, &url, |_| ()).await?,
+            }
+        }
+    }
+
+    async fn fetch_once(&self, url: &String, start: u64, end: u64) -> common::error::Result<Bytes> {
+        let mut watchdog = Watchdog::new(self.options.stall.as_ref());
+        let mut resp = watchdog
+            .response(self.network.request_range(url, start, end))
+            .await?;
+        let mut block = BytesMut::with_capacity((end - start) as usize);
+        while let Some(chunk) = watchdog.chunk(&mut resp).await? {
+            block.extend_from_slice(&chunk);
+        }
+        if block.len() as u64 != end - start {
+            return Err(DownloadError::size_mismatch(&format!(
+                "block {}-{} received {} bytes",
+                start,
+                end,
+                block.len()
+            )));
+        }
+        Ok(block.freeze())
+    }
+}
+
+impl BlockCache {
+    /// a hit makes the block the most recently used one
+    fn get(&mut self, index: u64) -> Option<Bytes> {
+        let block = self.blocks.get(&index)?.clone();
+        if let Some(position) = self.order.iter().position(|cached| *cached == index) {
+            self.order.remove(position);
+            self.order.push_back(index);
+        }
+        Some(block)
+    }
+
+    /// evicts the least recently used blocks beyond `capacity`
+    fn insert(&mut self, index: u64, block: Bytes, capacity: usize) {
+        if self.blocks.insert(index, block).is_none() {
+            self.order.push_back(index);
+        }
+        while self.order.len() > capacity.max(1) {
+            if let Some(oldest) = self.order.pop_front() {
+                self.blocks.remove(&oldest);
+            }
+        }
+    }
+}
+
+/// writes fetched blocks into the segment cache files of a full download
+struct Spill {
+    _lock: PathLock,
+    cache_dir: PathBuf,
+    segments: Vec<SpillSegment>,
+}
+
+struct SpillSegment {
+    thread_number: usize,
+    range_start: u64,
+    range_end: u64,
+    /// the segment's cache file holds `[range_start, cached_end)`
+    cached_end: u64,
+    file: File,
+}
+
+impl SpillSegment {
+    /// `[start, end)` reaches past the cached prefix without leaving a gap
+    fn continued_by(&self, start: u64, end: u64) -> bool {
+        self.cached_end < self.range_end && start <= self.cached_end && end > self.cached_end
+    }
+}
+
+impl Spill {
+    /// `None` when a download holds the cache right now
+    fn open(
+        url: &str,
+        remote_info: &RemoteInfo,
+        options: &SpillOptions,
+    ) -> common::error::Result<Option<Spill>> {
+        let length = remote_info.range_length().unwrap_or(0);
+        let download = Download {
+            url: url.to_owned(),
+            filename: options.output_file.clone(),
+            threads: options.threads,
+            options: options.download_options.clone(),
+            ..Default::default()
+        };
+        if length as usize <= download.options.small_file_threshold {
+            // small files are downloaded without a cache
+            return Ok(None);
+        }
+        let cache_dir = download.generate_cache_dir(0)?;
+        let lock = match PathLock::try_acquire(&Download::cache_lock_path(&cache_dir))? {
+            Some(lock) => lock,
+            None => return Ok(None),
+        };
+        Download::check_remote_info(&cache_dir, remote_info)?;
+        let file_name = options
+            .output_file
+            .file_name()
+            .ok_or(DownloadError::parameter(
+                "target file should not be a director",
+            ))?
+            .to_string_lossy()
+            .into_owned();
+        let (_, segments) = Download::calculate_ranges(
+            download.segment_count(),
+            0,
+            length as usize,
+            progress::Progress::default(),
+            cache_dir.clone(),
+            &file_name,
+        );
+        let mut spill_segments = Vec::with_capacity(segments.len());
+        for segment in segments {
+            let file = OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(Download::cache_file_name(
+                    &cache_dir,
+                    &file_name,
+                    segment.thread_number,
+                ))?;
+            file.set_len((segment.range_end - segment.range_start) as u64)?;
+            spill_segments.push(SpillSegment {
+                thread_number: segment.thread_number,
+                range_start: segment.range_start as u64,
+                range_end: segment.range_end as u64,
+                cached_end: segment.query_start.unwrap_or(segment.range_end) as u64,
+                file,
+            });
+        }
+        let spill = Spill {
+            _lock: lock,
+            cache_dir,
+            segments: spill_segments,
+        };
+        spill.dump()?;
+        Ok(Some(spill))
+    }
+
+    /// whether `len` bytes from `start` on continue the cache of a segment
+    fn wants(&self, start: u64, len: u64) -> bool {
+        self.segments
+            .iter()
+            .any(|segment| segment.continued_by(start, start + len))
+    }
+
+    /// keep the part of `data` (remote bytes from `start` on) that continues a segment's cache
+    fn write(&mut self, start: u64, data: &[u8]) -> common::error::Result<()> {
+        let end = start + data.len() as u64;
+        let mut changed = false;
+        for segment in &mut self.segments {
+            if !segment.continued_by(start, end) {
+                continue;
+            }
+            let write_end = end.min(segment.range_end);
+            segment
+                .file
+                .seek(SeekFrom::Start(segment.cached_end - segment.range_start))?;
+            segment.file.write_all(
+                &data[(segment.cached_end - start) as usize..(write_end - start) as usize],
+            )?;
+            segment.cached_end = write_end;
+            changed = true;
+        }
+        if changed {
+            self.dump()?;
+        }
+        Ok(())
+    }
+
+    fn dump(&self) -> common::error::Result<()> {
+        let status = self
+            .segments
+            .iter()
+            .map(|segment| {
+                (
+                    segment.thread_number,
+                    (
+                        segment.cached_end - segment.range_start,
+                        segment.cached_end == segment.range_end,
+                    ),
+                )
+            })
+            .collect::<HashMap<_, _>>();
+        Download::dump_process(status, self.cache_dir.clone())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Read, Seek, SeekFrom};
+    use std::sync::{Arc, Mutex};
+
+    use bytes::Bytes;
+    use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+    use super::super::test_server::{Reply, TestServer};
+    use super::{seek_position, AsyncRemoteFile, BlockCache, RemoteFile};
+    use crate::common::error::DownloadError;
+    use crate::common::options::{CacheLocation, DownloadOptions, RemoteFileOptions, SpillOptions};
+
+    /// the `Range` of every request a server got
+    type Requested = Arc<Mutex<Vec<Option<(u64, Option<u64>)>>>>;
+
+    /// a server for `data` that logs the ranges it is asked for
+    fn file_server(data: &[u8]) -> (TestServer, Requested) {
+        let served = data.to_vec();
+        let requested = Arc::new(Mutex::new(Vec::new()));
+        let log = requested.clone();
+        let server = TestServer::start(move |request| {
+            log.lock().unwrap().push(request.range);
+            Reply::file(&served, request)
+        });
+        (server, requested)
+    }
+
+    fn block_options(read_ahead: usize) -> RemoteFileOptions {
+        RemoteFileOptions {
+            block_size: 1000,
+            cache_blocks: 4,
+            read_ahead,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_seek_position() {
+        assert_eq!(seek_position(5, 100, SeekFrom::Start(7)).unwrap(), 7);
+        assert_eq!(seek_position(5, 100, SeekFrom::End(-10)).unwrap(), 90);
+        assert_eq!(seek_position(5, 100, SeekFrom::Current(3)).unwrap(), 8);
+        assert!(seek_position(5, 100, SeekFrom::Current(-6)).is_err());
+    }
+
+    #[test]
+    fn test_block_cache_evicts_oldest() {
+        let mut cache = BlockCache::default();
+        for index in 0..4 {
+            cache.insert(index, Bytes::from_static(b"x"), 3);
+        }
+        assert!(!cache.blocks.contains_key(&0));
+        assert!((1..4).all(|index| cache.blocks.contains_key(&index)));
+
+        // a hit keeps block 1, the least recently used one goes
+        assert!(cache.get(1).is_some());
+        cache.insert(4, Bytes::from_static(b"x"), 3);
+        assert!(!cache.blocks.contains_key(&2));
+        assert!([1, 3, 4]
+            .iter()
+            .all(|index| cache.blocks.contains_key(index)));
+    }
+
+    #[test]
+    fn test_remote_file_inside_runtime() {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let result = rt.block_on(async { RemoteFile::open("http://127.0.0.1:1/file.bin") });
+        assert!(matches!(result, Err(DownloadError::ParameterError(_))));
+    }
+
+    #[test]
+    fn test_remote_file_read_and_seek() {
+        let data: Vec<u8> = (0..10_000u32).map(|i| (i % 251) as u8).collect();
+        let (server, requested) = file_server(&data);
+        let mut file =
+            RemoteFile::open_with_options(server.url("/data.bin"), block_options(2)).unwrap();
+        assert_eq!(file.len(), 10_000);
+
+        // the first read fetches its block and the two after it
+        let mut head = vec![0u8; 2500];
+        file.read_exact(&mut head).unwrap();
+        assert_eq!(head, &data[..2500]);
+        assert_eq!(
+            requested.lock().unwrap()[1..],
+            [
+                Some((0, Some(999))),
+                Some((1000, Some(1999))),
+                Some((2000, Some(2999)))
+            ]
+        );
+        // served from the cache
+        let mut rest = vec![0u8; 500];
+        file.read_exact(&mut rest).unwrap();
+        assert_eq!(rest, &data[2500..3000]);
+        assert_eq!(requested.lock().unwrap().len(), 4);
+
+        assert_eq!(file.seek(SeekFrom::End(-100)).unwrap(), 9900);
+        let mut tail = Vec::new();
+        file.read_to_end(&mut tail).unwrap();
+        assert_eq!(tail, &data[9900..]);
+        // a jump doesn't read ahead
+        assert_eq!(
+            requested.lock().unwrap().last(),
+            Some(&Some((9000, Some(9999))))
+        );
+
+        assert_eq!(file.seek(SeekFrom::Current(-5050)).unwrap(), 4950);
+        let mut middle = vec![0u8; 100];
+        file.read_exact(&mut middle).unwrap();
+        assert_eq!(middle, &data[4950..5050]);
+    }
+
+    #[test]
+    fn test_async_remote_file() {
+        let data: Vec<u8> = (0..10_000u32).map(|i| (i % 251) as u8).collect();
+        let (server, _) = file_server(&data);
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let mut file =
+                AsyncRemoteFile::open_with_options(server.url("/data.bin"), block_options(1))
+                    .await
+                    .unwrap();
+            assert_eq!(file.len(), 10_000);
+            let mut all = Vec::new();
+            file.read_to_end(&mut all).await.unwrap();
+            assert_eq!(all, data);
+
+            file.seek(SeekFrom::Start(1234)).await.unwrap();
+            let mut part = vec![0u8; 3000];
+            file.read_exact(&mut part).await.unwrap();
+            assert_eq!(part, &data[1234..4234]);
+        });
+    }
+
+    #[test]
+    fn test_spill_then_resume() {
+        let data: Vec<u8> = (0..64 * 1024u32).map(|i| (i % 251) as u8).collect();
+        let (server, requested) = file_server(&data);
+        let url = server.url("/data.bin");
+        let temp = tempfile::tempdir().unwrap();
+        let target = temp.path().join("data.bin");
+        let download_options = DownloadOptions {
+            cache_location: CacheLocation::Dir(temp.path().to_path_buf()),
+            small_file_threshold: 1024,
+            ..Default::default()
+        };
+
+        // the first half that is read goes into the cache of the download's two segments
+        let options = RemoteFileOptions {
+            block_size: 4096,
+            spill: Some(SpillOptions {
+                output_file: target.clone(),
+                threads: 2,
+                download_options: download_options.clone(),
+            }),
+            ..Default::default()
+        };
+        let mut file = RemoteFile::open_with_options(&url, options).unwrap();
+        let mut half = vec![0u8; data.len() / 2];
+        file.read_exact(&mut half).unwrap();
+        assert_eq!(half, &data[..data.len() / 2]);
+        drop(file);
+
+        let before = requested.lock().unwrap().len();
+        let report =
+            crate::download_with_options(&url, 2, target.clone(), download_options).unwrap();
+        assert_eq!(std::fs::read(&target).unwrap(), data);
+        assert_eq!(report.resumed_bytes, data.len() as u64 / 2);
+        // only the probe asks for the spilled half
+        let requested = requested.lock().unwrap()[before..].to_vec();
+        assert!(requested.iter().all(|range| match range {
+            Some((0, Some(0))) => true,
+            Some((start, _)) => *start >= data.len() as u64 / 2,
+            None => false,
+        }));
+    }
+}
@@ -0,0 +1,321 @@
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use super::network::RemoteInfo;
+
+/// throughput is sampled over windows of this length for the peak
+const PEAK_WINDOW: Duration = Duration::from_secs(1);
+
+/// How a finished download went.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct DownloadReport {
+    /// the file that was written, after [`ExistingFilePolicy::Rename`](crate::common::options::ExistingFilePolicy::Rename)
+    pub path: PathBuf,
+    pub url: String,
+    /// the url after redirects, `None` when the server wasn't asked
+    pub final_url: Option<String>,
+    pub etag: Option<String>,
+    pub total_bytes: u64,
+    /// bytes taken from the segment cache or an adopted target file
+    pub resumed_bytes: u64,
+    /// bytes transferred by this run
+    pub fetched_bytes: u64,
+    pub elapsed: Duration,
+    /// fetched bytes per second over the whole run
+    pub average_throughput: f64,
+    /// the best fetched bytes per second over one second of the run
+    pub peak_throughput: f64,
+    pub segments: Vec<SegmentReport>,
+    /// hex sha256 of the written file, `None` when nothing was written or it wasn't hashed
+    pub sha256: Option<String>,
+}
+
+/// one segment of a [`DownloadReport`], `[start, end)` in the remote file
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SegmentReport {
+    pub thread_number: usize,
+    pub start: u64,
+    pub end: u64,
+    /// bytes of the segment that were in the cache already
+    pub resumed_bytes: u64,
+    pub fetched_bytes: u64,
+    /// requests repeated after a failure
+    pub retries: usize,
+    /// time spent fetching, zero for a segment served from the cache
+    pub elapsed: Duration,
+    /// fetched bytes per second while fetching
+    pub throughput: f64,
+}
+
+/// collects the numbers of a [`DownloadReport`] while the download runs. the fetched bytes
+/// are atomics counted through a [`FetchCounter`], the peak throughput is sampled by
+/// [`TransferStats::sample`] instead of on every chunk
+pub(crate) struct TransferStats {
+    started: Instant,
+    fetched: Arc<AtomicU64>,
+    record: Mutex<Record>,
+}
+
+struct Record {
+    remote: Option<RemoteInfo>,
+    adopted: u64,
+    sha256: Option<String>,
+    /// the total fetched bytes when the current window started
+    window_start: Instant,
+    window_fetched: u64,
+    peak: f64,
+    segments: BTreeMap<usize, SegmentRecord>,
+}
+
+struct SegmentRecord {
+    report: SegmentReport,
+    started: Option<Instant>,
+    fetched: Arc<AtomicU64>,
+}
+
+/// counts the bytes fetched by one segment, and the total with them
+pub(crate) struct FetchCounter {
+    total: Arc<AtomicU64>,
+    segment: Arc<AtomicU64>,
+}
+
+impl FetchCounter {
+    pub fn add(&self, bytes: u64) {
+        self.segment.fetch_add(bytes, Ordering::Relaxed);
+        self.total.fetch_add(bytes, Ordering::Relaxed);
+    }
+}
+
+fn throughput(bytes: u64, elapsed: Duration) -> f64 {
+    if elapsed.is_zero() {
+        0.0
+    } else {
+        bytes as f64 / elapsed.as_secs_f64()
+    }
+}
+
+impl Default for TransferStats {
+    fn default() -> TransferStats {
+        let now = Instant::now();
+        TransferStats {
+            started: now,
+            fetched: Arc::new(AtomicU64::new(0)),
+            record: Mutex::new(Record {
+                remote: None,
+                adopted: 0,
+                sha256: None,
+                window_start: now,
+                window_fetched: 0,
+                peak: 0.0,
+                segments: BTreeMap::new(),
+            }),
+        }
+    }
+}
+
+impl TransferStats {
+    pub fn set_remote(&self, remote: &RemoteInfo) {
+        self.record.lock().unwrap().remote = Some(remote.clone());
+    }
+
+    /// bytes of an existing target file kept as the start of the download
+    pub fn set_adopted(&self, adopted: u64) {
+        self.record.lock().unwrap().adopted = adopted;
+    }
+
+    pub fn set_sha256(&self, sha256: Option<String>) {
+        self.record.lock().unwrap().sha256 = sha256;
+    }
+
+    /// register segment `thread_number` over `[start, end)`, `resumed` bytes of it are cached
+    pub fn add_segment(&self, thread_number: usize, start: u64, end: u64, resumed: u64) {
+        let segment = SegmentReport {
+            thread_number,
+            start,
+            end,
+            resumed_bytes: resumed,
+            fetched_bytes: 0,
+            retries: 0,
+            elapsed: Duration::ZERO,
+            throughput: 0.0,
+        };
+        let mut record = self.record.lock().unwrap();
+        record.segments.insert(
+            thread_number,
+            SegmentRecord {
+                report: segment,
+                started: None,
+                fetched: Arc::new(AtomicU64::new(0)),
+            },
+        );
+    }
+
+    pub fn start_segment(&self, thread_number: usize) {
+        let mut record = self.record.lock().unwrap();
+        if let Some(segment) = record.segments.get_mut(&thread_number) {
+            segment.started.get_or_insert_with(Instant::now);
+        }
+    }
+
+    pub fn retry(&self, thread_number: usize) {
+        let mut record = self.record.lock().unwrap();
+        if let Some(segment) = record.segments.get_mut(&thread_number) {
+            segment.report.retries += 1;
+        }
+    }
+
+    /// the counter for the chunks of segment `thread_number`, taken once before they arrive
+    pub fn counter(&self, thread_number: usize) -> FetchCounter {
+        let record = self.record.lock().unwrap();
+        let segment = match record.segments.get(&thread_number) {
+            Some(segment) => segment.fetched.clone(),
+            None => Arc::new(AtomicU64::new(0)),
+        };
+        FetchCounter {
+            total: self.fetched.clone(),
+            segment,
+        }
+    }
+
+    /// bytes transferred so far
+    pub fn fetched_bytes(&self) -> u64 {
+        self.fetched.load(Ordering::Relaxed)
+    }
+
+    /// close the current throughput window for the peak once it is [`PEAK_WINDOW`] long,
+    /// called at the download's checkpoints
+    pub fn sample(&self) {
+        let now = Instant::now();
+        let fetched = self.fetched_bytes();
+        let mut record = self.record.lock().unwrap();
+        let window = now - record.window_start;
+        if window >= PEAK_WINDOW {
+            let bytes = fetched.saturating_sub(record.window_fetched);
+            record.peak = record.peak.max(throughput(bytes, window));
+            record.window_start = now;
+            record.window_fetched = fetched;
+        }
+    }
+
+    /// a body of unknown length stretches the segment to the bytes received
+    pub fn finish_segment(&self, thread_number: usize) {
+        let mut record = self.record.lock().unwrap();
+        if let Some(SegmentRecord {
+            report: segment,
+            started: Some(started),
+            fetched,
+        }) = record.segments.get_mut(&thread_number)
+        {
+            segment.fetched_bytes = fetched.load(Ordering::Relaxed);
+            segment.elapsed = started.elapsed();
+            segment.throughput = throughput(segment.fetched_bytes, segment.elapsed);
+            let received = segment.start + segment.resumed_bytes + segment.fetched_bytes;
+            segment.end = segment.end.max(received);
+        }
+    }
+
+    /// forget the segments, their bytes and an adopted prefix, e.g. before falling back to a
+    /// plain request
+    pub fn restart(&self) {
+        let mut record = self.record.lock().unwrap();
+        record.adopted = 0;
+        self.fetched.store(0, Ordering::Relaxed);
+        record.window_start = Instant::now();
+        record.window_fetched = 0;
+        record.segments.clear();
+    }
+
+    pub fn report(&self, url: &str, path: PathBuf, total_bytes: u64) -> DownloadReport {
+        let elapsed = self.started.elapsed();
+        let fetched = self.fetched_bytes();
+        let record = self.record.lock().unwrap();
+        // a run shorter than the window still has a peak
+        let window = record.window_start.elapsed();
+        let peak = record.peak.max(throughput(
+            fetched.saturating_sub(record.window_fetched),
+            window.max(PEAK_WINDOW.min(elapsed)),
+        ));
+        let cached: u64 = record
+            .segments
+            .values()
+            .map(|segment| segment.report.resumed_bytes)
+            .sum();
+        let resumed_bytes = (record.adopted + cached).min(total_bytes);
+        DownloadReport {
+            path,
+            url: url.to_owned(),
+            final_url: record
+                .remote
+                .as_ref()
+                .map(|remote| remote.final_url.clone()),
+            etag: record
+                .remote
+                .as_ref()
+                .and_then(|remote| remote.etag.clone()),
+            total_bytes,
+            resumed_bytes,
+            fetched_bytes: fetched,
+            elapsed,
+            average_throughput: throughput(fetched, elapsed),
+            peak_throughput: peak,
+            segments: record
+                .segments
+                .values()
+                .map(|segment| SegmentReport {
+                    fetched_bytes: segment.fetched.load(Ordering::Relaxed),
+                    ..segment.report.clone()
+                })
+                .collect(),
+            sha256: record.sha256.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+
+    use super::TransferStats;
+
+    #[test]
+    fn test_transfer_stats() {
+        let stats = TransferStats::default();
+        stats.set_adopted(10);
+        stats.add_segment(1, 10, 60, 20);
+        stats.add_segment(2, 60, 100, 0);
+        stats.start_segment(1);
+        stats.counter(1).add(30);
+        stats.finish_segment(1);
+        stats.start_segment(2);
+        stats.retry(2);
+        let counter = stats.counter(2);
+        counter.add(15);
+        counter.add(25);
+        assert_eq!(stats.fetched_bytes(), 70);
+        stats.sample();
+        stats.finish_segment(2);
+
+        let report = stats.report("http://localhost/a", PathBuf::from("a"), 100);
+        assert_eq!(report.resumed_bytes, 30);
+        assert_eq!(report.fetched_bytes, 70);
+        assert_eq!(report.segments.len(), 2);
+        assert_eq!(report.segments[0].fetched_bytes, 30);
+        assert_eq!(report.segments[1].retries, 1);
+        assert_eq!(report.segments[1].fetched_bytes, 40);
+        assert!(report.peak_throughput > 0.0);
+
+        stats.restart();
+        stats.add_segment(1, 0, 0, 0);
+        stats.start_segment(1);
+        stats.counter(1).add(100);
+        stats.finish_segment(1);
+        let report = stats.report("http://localhost/a", PathBuf::from("a"), 100);
+        assert_eq!(report.fetched_bytes, 100);
+        assert_eq!(report.segments[0].end, 100);
+    }
+}
@@ -0,0 +1,73 @@
+use std::time::Duration;
+
+use crate::common;
+use crate::common::error::DownloadError;
+
+use super::network::{self, Network};
+use super::refresh::SegmentUrl;
+
+/// The retry decisions shared by every ranged fetch: a segment, a streamed chunk or a block of
+/// a remote file. The caller makes the attempts, this tells what follows a failed one.
+pub(crate) struct Retries<'a> {
+    network: &'a Network,
+    url: &'a SegmentUrl,
+    retries: usize,
+    attempt: usize,
+}
+
+/// the next attempt after a failed one
+pub(crate) struct Scheduled {
+    pub attempt: usize,
+    /// already waited out when [`Retries::schedule`] returns
+    pub delay: Duration,
+    pub error: DownloadError,
+}
+
+impl<'a> Retries<'a> {
+    pub fn new(network: &'a Network, url: &'a SegmentUrl, retries: usize) -> Retries<'a> {
+        Retries {
+            network,
+            url,
+            retries,
+            attempt: 0,
+        }
+    }
+
+    /// the attempt with `url` failed with `error`. returns the error when it can't be retried or
+    /// the retries are used up, otherwise waits until the next attempt is due. `scheduled` is
+    /// told about the attempt before the wait starts
+    pub async fn schedule(
+        &mut self,
+        error: DownloadError,
+        url: &str,
+        scheduled: impl FnOnce(&Scheduled),
+    ) -> common::error::Result<()> {
+        // a server that mis-answers ranges is fallen back from, not asked again
+        if matches!(error, DownloadError::UnexpectedRange(_)) || self.attempt >= self.retries {
+            return Err(error);
+        }
+        self.attempt += 1;
+        let delay = match error {
+            // the pause is shared, every connection waits out the same `Retry-After`
+            DownloadError::RateLimited(_) => self.network.rate_limit_pause(),
+            // e.g. an expired presigned url, continue with a fresh one
+            ref e if network::is_refused(e) => {
+                if self.url.refresh(self.network, url).await?.is_none() {
+                    return Err(error);
+                }
+                self.url.retry_delay(self.attempt)
+            }
+            _ => {
+                self.network.throttle().failed();
+                Duration::from_secs(self.attempt as u64)
+            }
+        };
+        scheduled(&Scheduled {
+            attempt: self.attempt,
+            delay,
+            error,
+        });
+        tokio::time::sleep(delay).await;
+        Ok(())
+    }
+}
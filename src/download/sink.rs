@@ -0,0 +1,252 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use sha2::{Digest, Sha256};
+
+use crate::common;
+
+/// Random-access storage a download writes into. Segments arrive out of order, each one is
+/// written front to back with [`StorageSink::write_at`].
+pub trait StorageSink: Send {
+    /// write all of `buf` at `offset`, counted from the start of the downloaded bytes
+    fn write_at(&mut self, offset: u64, buf: &[u8]) -> io::Result<()>;
+    /// called with the full length before the first write when the length is known
+    fn set_len(&mut self, len: u64) -> io::Result<()>;
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+    /// make the written bytes durable
+    fn sync(&mut self) -> io::Result<()> {
+        self.flush()
+    }
+    /// called once after every byte has been written
+    fn finalize(&mut self) -> common::error::Result<()> {
+        Ok(self.sync()?)
+    }
+}
+
+/// A local file, written from offset 0.
+pub struct FileSink {
+    file: File,
+}
+
+impl FileSink {
+    pub fn new(file: File) -> FileSink {
+        FileSink { file }
+    }
+
+    /// create or truncate `path`
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<FileSink> {
+        Ok(FileSink::new(File::create(path)?))
+    }
+
+    pub fn into_inner(self) -> File {
+        self.file
+    }
+}
+
+impl StorageSink for FileSink {
+    fn write_at(&mut self, offset: u64, buf: &[u8]) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.write_all(buf)
+    }
+
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        self.file.set_len(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.file.sync_all()
+    }
+}
+
+/// An in-memory buffer, for small files or for checking results without touching the disk.
+#[derive(Debug, Default)]
+pub struct MemorySink {
+    data: Vec<u8>,
+}
+
+impl MemorySink {
+    pub fn new() -> MemorySink {
+        MemorySink::default()
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn into_inner(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+impl StorageSink for MemorySink {
+    fn write_at(&mut self, offset: u64, buf: &[u8]) -> io::Result<()> {
+        let start = offset as usize;
+        let end = start + buf.len();
+        if self.data.len() < end {
+            self.data.resize(end, 0);
+        }
+        self.data[start..end].copy_from_slice(buf);
+        Ok(())
+    }
+
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        self.data.resize(len as usize, 0);
+        Ok(())
+    }
+}
+
+/// Writes into a caller's existing file from `offset` on, e.g. one member of a pack file.
+/// The rest of the file is left alone, it is only ever grown to fit the download.
+pub struct OffsetSink {
+    file: File,
+    offset: u64,
+}
+
+impl OffsetSink {
+    pub fn new(file: File, offset: u64) -> OffsetSink {
+        OffsetSink { file, offset }
+    }
+
+    /// open the existing file at `path` for writing
+    pub fn open<P: AsRef<Path>>(path: P, offset: u64) -> io::Result<OffsetSink> {
+        let file = OpenOptions::new().write(true).open(path)?;
+        Ok(OffsetSink::new(file, offset))
+    }
+
+    pub fn into_inner(self) -> File {
+        self.file
+    }
+}
+
+impl StorageSink for OffsetSink {
+    fn write_at(&mut self, offset: u64, buf: &[u8]) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start(self.offset + offset))?;
+        self.file.write_all(buf)
+    }
+
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        let required = self.offset + len;
+        if self.file.metadata()?.len() < required {
+            self.file.set_len(required)?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.file.sync_data()
+    }
+}
+
+/// hashes the bytes passing through to `sink`, as long as they are written front to back
+pub(crate) struct DigestSink<'a> {
+    sink: &'a mut dyn StorageSink,
+    hasher: Sha256,
+    position: u64,
+    in_order: bool,
+}
+
+impl<'a> DigestSink<'a> {
+    pub fn new(sink: &'a mut dyn StorageSink) -> DigestSink<'a> {
+        DigestSink {
+            sink,
+            hasher: Sha256::new(),
+            position: 0,
+            in_order: true,
+        }
+    }
+
+    /// hex sha256 of everything written, `None` when the writes were out of order
+    pub fn sha256(self) -> Option<String> {
+        self.in_order.then(|| hex::encode(self.hasher.finalize()))
+    }
+}
+
+impl StorageSink for DigestSink<'_> {
+    fn write_at(&mut self, offset: u64, buf: &[u8]) -> io::Result<()> {
+        if self.in_order && offset == self.position {
+            self.hasher.update(buf);
+            self.position += buf.len() as u64;
+        } else {
+            self.in_order = false;
+        }
+        self.sink.write_at(offset, buf)
+    }
+
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        self.sink.set_len(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.sink.flush()
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.sink.sync()
+    }
+
+    fn finalize(&mut self) -> common::error::Result<()> {
+        self.sink.finalize()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use super::{DigestSink, MemorySink, OffsetSink, StorageSink};
+
+    #[test]
+    fn test_memory_sink() {
+        let mut sink = MemorySink::new();
+        sink.set_len(6).unwrap();
+        sink.write_at(3, b"def").unwrap();
+        sink.write_at(0, b"abc").unwrap();
+        sink.write_at(6, b"g").unwrap();
+        sink.finalize().unwrap();
+        assert_eq!(sink.into_inner(), b"abcdefg");
+    }
+
+    #[test]
+    fn test_digest_sink() {
+        let mut memory = MemorySink::new();
+        let mut sink = DigestSink::new(&mut memory);
+        sink.write_at(0, b"a").unwrap();
+        sink.write_at(1, b"bc").unwrap();
+        assert_eq!(
+            sink.sha256().unwrap(),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+
+        let mut sink = DigestSink::new(&mut memory);
+        sink.write_at(1, b"bc").unwrap();
+        sink.write_at(0, b"a").unwrap();
+        assert_eq!(sink.sha256(), None);
+    }
+
+    #[test]
+    fn test_offset_sink() {
+        let temp = tempfile::tempdir().unwrap();
+        let path = temp.path().join("pack.bin");
+        fs::write(&path, b"0123456789").unwrap();
+        let mut sink = OffsetSink::open(&path, 4).unwrap();
+        sink.set_len(3).unwrap();
+        sink.write_at(1, b"bc").unwrap();
+        sink.write_at(0, b"a").unwrap();
+        sink.finalize().unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"0123abc789");
+
+        sink.set_len(8).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().len(), 12);
+    }
+}
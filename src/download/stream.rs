@@ -0,0 +1,378 @@
+use std::io::{self, Read};
+use std::sync::Arc;
+
+use bytes::{Bytes, BytesMut};
+use futures::stream::{self, StreamExt};
+use futures::FutureExt;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::sync::{mpsc, Semaphore};
+
+use super::events::Events;
+use super::network::Network;
+use super::refresh::SegmentUrl;
+use super::retry::Retries;
+use super::watchdog::Watchdog;
+use crate::common;
+use crate::common::error::DownloadError;
+use crate::common::options::{StallPolicy, StreamOptions};
+
+/// Where the bytes of a streaming download go, strictly in order.
+pub(crate) enum Output<'a, W: AsyncWrite + Unpin + ?Sized> {
+    Writer(&'a mut W),
+    Channel(mpsc::Sender<common::error::Result<Bytes>>),
+}
+
+impl<W: AsyncWrite + Unpin + ?Sized> Output<'_, W> {
+    async fn emit(&mut self, bytes: Bytes) -> common::error::Result<()> {
+        match self {
+            Output::Writer(writer) => writer.write_all(&bytes).await?,
+            Output::Channel(sender) => sender
+                .send(Ok(bytes))
+                .await
+                .map_err(|_| DownloadError::system("the reader was dropped"))?,
+        }
+        Ok(())
+    }
+
+    async fn finish(&mut self) -> common::error::Result<()> {
+        if let Output::Writer(writer) = self {
+            writer.flush().await?;
+        }
+        Ok(())
+    }
+}
+
+/// fetch `url` in chunks over up to `threads` connections and hand the chunks to `output` in
+/// order, returns the number of bytes emitted
+pub(crate) async fn stream<W: AsyncWrite + Unpin + ?Sized>(
+    url: &String,
+    threads: usize,
+    options: &StreamOptions,
+    output: &mut Output<'_, W>,
+) -> common::error::Result<u64> {
+    if options.chunk_size == 0 {
+        return Err(DownloadError::parameter("chunk size should not be 0"));
+    }
+    let network = Arc::new(Network::default());
+    let remote = network.probe(url).await?;
+    let content_length = match remote.range_length() {
+        Some(content_length) => content_length,
+        None => return single_stream(&network, url, output, 0).await,
+    };
+    let segment_url = Arc::new(SegmentUrl::new(
+        url.clone(),
+        options.refresh_url.clone(),
+        Some(remote),
+        Events::new(None),
+    ));
+
+    let connections = Arc::new(Semaphore::new(threads.max(1)));
+    let shared_options = Arc::new(options.clone());
+    let chunk_count = content_length.div_ceil(options.chunk_size);
+    // `buffered` runs at most `buffer_chunks` fetches and yields them in order, chunks that are
+    // done early wait in the buffer and no new fetch starts until the oldest one is emitted.
+    // every fetch is a task of its own so it keeps reading while `emit` waits for the consumer,
+    // its stall watchdog would take that wait for a stalled response otherwise. dropping the
+    // handle cancels the fetch
+    let mut chunks = stream::iter(0..chunk_count)
+        .map(|index| {
+            let start = index * options.chunk_size;
+            let end = (start + options.chunk_size).min(content_length);
+            let network = network.clone();
+            let connections = connections.clone();
+            let segment_url = segment_url.clone();
+            let options = shared_options.clone();
+            let (fetch, handle) = async move {
+                let _permit = connections
+                    .acquire_owned()
+                    .await
+                    .map_err(|e| DownloadError::system(&e.to_string()))?;
+                fetch_chunk(&network, &segment_url, start, end, &options).await
+            }
+            .remote_handle();
+            tokio::spawn(fetch);
+            handle
+        })
+        .buffered(options.buffer_chunks.max(1));
+
+    let mut emitted = 0;
+    while let Some(chunk) = chunks.next().await {
+        match chunk {
+            Ok(chunk) => {
+                emitted += chunk.len() as u64;
+                output.emit(chunk).await?;
+            }
+            // the server doesn't do ranges (properly), continue with the plain body
+            Err(DownloadError::UnexpectedRange(_)) => {
+                drop(chunks);
+                return single_stream(&network, url, output, emitted).await;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    output.finish().await?;
+    Ok(emitted)
+}
+
+/// one plain request, for servers without ranges or a known length. it can't be resumed.
+/// the first `skip` bytes were emitted already and are dropped
+async fn single_stream<W: AsyncWrite + Unpin + ?Sized>(
+    network: &Network,
+    url: &String,
+    output: &mut Output<'_, W>,
+    skip: u64,
+) -> common::error::Result<u64> {
+    let _permit = network.connection().await;
+    let resp = network.make_request(url, None).await?;
+    let mut resp = network.error_for_status(resp)?;
+    let mut received = 0;
+    while let Some(mut chunk) = resp.chunk().await? {
+        let chunk_start = received;
+        received += chunk.len() as u64;
+        if received <= skip {
+            continue;
+        }
+        if chunk_start < skip {
+            let _ = chunk.split_to((skip - chunk_start) as usize);
+        }
+        output.emit(chunk).await?;
+    }
+    if received < skip {
+        return Err(DownloadError::size_mismatch(&format!(
+            "the plain response ended after {} bytes, {} were emitted already",
+            received, skip
+        )));
+    }
+    output.finish().await?;
+    Ok(received)
+}
+
+/// fetch `[start, end)` into memory. a broken or stalled response is resumed from the last
+/// received byte, up to `retries` times
+async fn fetch_chunk(
+    network: &Network,
+    segment_url: &SegmentUrl,
+    start: u64,
+    end: u64,
+    options: &StreamOptions,
+) -> common::error::Result<Bytes> {
+    let mut chunk = BytesMut::with_capacity((end - start) as usize);
+    let mut retry = Retries::new(network, segment_url, options.retries);
+    loop {
+        let offset = start + chunk.len() as u64;
+        let stall = options.stall.as_ref();
+        let url = segment_url.get();
+        let permit = network.connection().await;
+        let result = fetch_chunk_once(network, &url, offset, end, &mut chunk, stall).await;
+        drop(permit);
+        let error = match result {
+            Ok(()) if chunk.len() as u64 == end - start => return Ok(chunk.freeze()),
+            Ok(()) => DownloadError::size_mismatch(&format!(
+                "chunk {}-{} ended after {} bytes",
+                start,
+                end,
+                chunk.len()
+            )),
+            Err(e) => e,
+        };
+        retry.schedule(error, &url, |_| ()).await?;
+    }
+}
+
+async fn fetch_chunk_once(
+    network: &Network,
+    url: &String,
+    offset: u64,
+    end: u64,
+    chunk: &mut BytesMut,
+    stall: Option<&StallPolicy>,
+) -> common::error::Result<()> {
+    let mut watchdog = Watchdog::new(stall);
+    let mut resp = watchdog
+        .response(network.request_range(url, offset, end))
+        .await?;
+    while let Some(bytes) = watchdog.chunk(&mut resp).await? {
+        if offset + (chunk.len() + bytes.len()) as u64 > end {
+            return Err(DownloadError::unexpected_range(&format!(
+                "received more than {} bytes",
+                end
+            )));
+        }
+        chunk.extend_from_slice(&bytes);
+    }
+    Ok(())
+}
+
+/// A [`Read`] handle over a streaming download, the download runs on a background thread and
+/// stops when the handle is dropped.
+pub struct StreamReader {
+    receiver: mpsc::Receiver<common::error::Result<Bytes>>,
+    current: Bytes,
+}
+
+impl StreamReader {
+    pub(crate) fn spawn(
+        url: String,
+        threads: usize,
+        options: StreamOptions,
+    ) -> common::error::Result<StreamReader> {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        let (sender, receiver) = mpsc::channel(1);
+        std::thread::spawn(move || {
+            runtime.block_on(async move {
+                let mut output = Output::<tokio::io::Sink>::Channel(sender.clone());
+                if let Err(e) = stream(&url, threads, &options, &mut output).await {
+                    let _ = sender.send(Err(e)).await;
+                }
+            })
+        });
+        Ok(StreamReader {
+            receiver,
+            current: Bytes::new(),
+        })
+    }
+}
+
+impl Read for StreamReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.current.is_empty() {
+            match self.receiver.blocking_recv() {
+                Some(chunk) => self.current = chunk?,
+                None => return Ok(0),
+            }
+        }
+        let count = buf.len().min(self.current.len());
+        buf[..count].copy_from_slice(&self.current.split_to(count));
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Read;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::Duration;
+
+    use super::super::test_server::{Reply, TestServer};
+    use super::StreamReader;
+    use crate::common::options::{StallPolicy, StreamOptions, UrlRefresh};
+
+    fn options(chunk_size: u64, buffer_chunks: usize) -> StreamOptions {
+        StreamOptions {
+            chunk_size,
+            buffer_chunks,
+            retries: 0,
+            stall: None,
+            refresh_url: None,
+        }
+    }
+
+    #[test]
+    fn test_chunks_are_delivered_in_order() {
+        let data = (0..10_000).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        let served = data.clone();
+        // the earlier a chunk starts the later it is answered, so they complete in reverse
+        let server = TestServer::start(move |request| {
+            if let Some((first, Some(last))) = request.range {
+                if last > 0 {
+                    thread::sleep(Duration::from_millis(200 - first / 50));
+                }
+            }
+            Reply::file(&served, request)
+        });
+        let mut reader = StreamReader::spawn(server.url("/file"), 4, options(1000, 4)).unwrap();
+        let mut received = Vec::new();
+        reader.read_to_end(&mut received).unwrap();
+        assert_eq!(received, data);
+    }
+
+    #[test]
+    fn test_reorder_buffer_is_bounded() {
+        let data = vec![7u8; 10_000];
+        let served = data.clone();
+        let first_done = Arc::new(AtomicBool::new(false));
+        let started_before = Arc::new(Mutex::new(Vec::new()));
+        let (done, started) = (first_done.clone(), started_before.clone());
+        // chunk 0 is held back, the chunks after it finish but can't be emitted yet
+        let server = TestServer::start(move |request| {
+            if let Some((first, Some(last))) = request.range {
+                if last > 0 && !done.load(Ordering::SeqCst) {
+                    started.lock().unwrap().push(first);
+                }
+                if first == 0 && last > 0 {
+                    thread::sleep(Duration::from_millis(500));
+                    done.store(true, Ordering::SeqCst);
+                }
+            }
+            Reply::file(&served, request)
+        });
+        let mut reader = StreamReader::spawn(server.url("/file"), 8, options(1000, 3)).unwrap();
+        let mut received = Vec::new();
+        reader.read_to_end(&mut received).unwrap();
+        assert_eq!(received, data);
+        // 8 connections are allowed, but only the 3 buffered chunks are fetched meanwhile
+        let mut started = started_before.lock().unwrap().clone();
+        started.sort();
+        assert_eq!(started, vec![0, 1000, 2000]);
+        // the probe and one request per chunk
+        assert_eq!(server.requests(), 11);
+    }
+
+    #[test]
+    fn test_slow_reader_does_not_stall_chunks() {
+        let data = (0..512 * 1024).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        let served = data.clone();
+        let server = TestServer::start(move |request| Reply::file(&served, request));
+        // no retries, a chunk taken for stalled fails the stream
+        let options = StreamOptions {
+            stall: Some(StallPolicy {
+                timeout: Duration::from_millis(100),
+                min_throughput: Some(1024 * 1024),
+                window: Duration::from_millis(20),
+            }),
+            ..options(64 * 1024, 4)
+        };
+        let mut reader = StreamReader::spawn(server.url("/file"), 4, options).unwrap();
+        let mut received = Vec::new();
+        let mut buf = vec![0; 64 * 1024];
+        loop {
+            let count = reader.read(&mut buf).unwrap();
+            if count == 0 {
+                break;
+            }
+            received.extend_from_slice(&buf[..count]);
+            // the reader takes longer than the timeout for every chunk
+            thread::sleep(Duration::from_millis(150));
+        }
+        assert_eq!(received, data);
+        // the probe and one request per chunk, nothing was fetched twice
+        assert_eq!(server.requests(), 9);
+    }
+
+    #[test]
+    fn test_refused_chunk_is_refreshed() {
+        let data = (0..10_000).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        let served = data.clone();
+        let server = TestServer::start(move |request| {
+            let probe = request.range == Some((0, Some(0)));
+            match request.path.as_str() {
+                "/file?sig=old" if !probe => Reply::status(403),
+                _ => Reply::file(&served, request),
+            }
+        });
+        let fresh = server.url("/file?sig=new");
+        let options = StreamOptions {
+            retries: 1,
+            refresh_url: Some(UrlRefresh::new(move |_| Some(fresh.clone()))),
+            ..options(1000, 4)
+        };
+        let mut reader = StreamReader::spawn(server.url("/file?sig=old"), 4, options).unwrap();
+        let mut received = Vec::new();
+        reader.read_to_end(&mut received).unwrap();
+        assert_eq!(received, data);
+    }
+}
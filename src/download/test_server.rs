@@ -0,0 +1,140 @@
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+pub(crate) struct Request {
+    pub path: String,
+    /// first and last byte of a `Range: bytes=a-b` header, `None` for an open end
+    pub range: Option<(u64, Option<u64>)>,
+}
+
+pub(crate) struct Reply {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+type Handler = dyn Fn(&Request) -> Reply + Send + Sync;
+
+/// A minimal HTTP/1.1 server on a local port for the tests, one thread per connection and
+/// `Connection: close` after every response.
+pub(crate) struct TestServer {
+    port: u16,
+    requests: Arc<AtomicUsize>,
+}
+
+impl Reply {
+    pub fn status(status: u16) -> Reply {
+        Reply {
+            status,
+            headers: Vec::new(),
+            body: Vec::new(),
+        }
+    }
+
+    /// `data` as a server with range support answers `request`
+    pub fn file(data: &[u8], request: &Request) -> Reply {
+        let total = data.len() as u64;
+        match request.range {
+            Some((first, _)) if first >= total => {
+                Reply::status(416).header("Content-Range", &format!("bytes */{}", total))
+            }
+            Some((first, last)) => {
+                let last = last.unwrap_or(total - 1).min(total - 1);
+                let mut reply = Reply::status(206).header(
+                    "Content-Range",
+                    &format!("bytes {}-{}/{}", first, last, total),
+                );
+                reply.body = data[first as usize..=last as usize].to_vec();
+                reply
+            }
+            None => {
+                let mut reply = Reply::status(200);
+                reply.body = data.to_vec();
+                reply
+            }
+        }
+    }
+
+    pub fn header(mut self, name: &str, value: &str) -> Reply {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// keep only the first `len` bytes of the body, `Content-Length` says so too
+    pub fn truncate(mut self, len: usize) -> Reply {
+        self.body.truncate(len);
+        self
+    }
+}
+
+impl TestServer {
+    pub fn start(handler: impl Fn(&Request) -> Reply + Send + Sync + 'static) -> TestServer {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let requests = Arc::new(AtomicUsize::new(0));
+        let handler: Arc<Handler> = Arc::new(handler);
+        let counter = requests.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let handler = handler.clone();
+                let counter = counter.clone();
+                thread::spawn(move || {
+                    if let Some(request) = read_request(&stream) {
+                        counter.fetch_add(1, Ordering::SeqCst);
+                        let _ = write_reply(stream, handler(&request));
+                    }
+                });
+            }
+        });
+        TestServer { port, requests }
+    }
+
+    pub fn url(&self, path: &str) -> String {
+        format!("http://127.0.0.1:{}{}", self.port, path)
+    }
+
+    /// how many requests were answered so far
+    pub fn requests(&self) -> usize {
+        self.requests.load(Ordering::SeqCst)
+    }
+}
+
+fn read_request(stream: &TcpStream) -> Option<Request> {
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    reader.read_line(&mut line).ok()?;
+    let path = line.split_whitespace().nth(1)?.to_string();
+    let mut range = None;
+    loop {
+        line.clear();
+        if reader.read_line(&mut line).ok()? == 0 || line.trim().is_empty() {
+            break;
+        }
+        let Some((name, value)) = line.split_once(':') else {
+            continue;
+        };
+        if name.eq_ignore_ascii_case("range") {
+            let (first, last) = value.trim().strip_prefix("bytes=")?.split_once('-')?;
+            range = Some((first.parse().ok()?, last.parse().ok()));
+        }
+    }
+    Some(Request { path, range })
+}
+
+fn write_reply(mut stream: TcpStream, reply: Reply) -> std::io::Result<()> {
+    let mut head = format!(
+        "HTTP/1.1 {} Test\r\nContent-Length: {}\r\nConnection: close\r\n",
+        reply.status,
+        reply.body.len()
+    );
+    for (name, value) in &reply.headers {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    head.push_str("\r\n");
+    stream.write_all(head.as_bytes())?;
+    stream.write_all(&reply.body)?;
+    stream.flush()
+}
@@ -0,0 +1,358 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::sync::Notify;
+use tokio::time::Instant;
+use tracing::{info, warn};
+
+/// the pause after a `429` or `503` without a usable `Retry-After`
+const DEFAULT_PAUSE: Duration = Duration::from_secs(5);
+/// a reduced connection limit doubles again after this long without another rate limit
+const RESTORE_INTERVAL: Duration = Duration::from_secs(10);
+/// an adaptive step up has to raise the throughput by this share to be kept
+const MIN_GAIN: f64 = 0.1;
+/// a steady adaptive count tries one more connection after this long
+const HOLD: Duration = Duration::from_secs(30);
+
+/// Shared by the connections of one download. A `429` or `503` pauses all of them until the
+/// server's `Retry-After` and halves how many may be open at once, the limit grows back step by
+/// step while the server stays quiet. In adaptive mode the limit follows the measured
+/// throughput instead, see [`AdaptiveConnections`](crate::common::options::AdaptiveConnections).
+#[derive(Default)]
+pub(crate) struct Throttle {
+    state: Mutex<State>,
+    released: Notify,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    active: usize,
+    waiting: usize,
+    /// the most connections wanted at once, the limit is lifted when it grows back to it
+    demand: usize,
+    limit: Option<usize>,
+    paused_until: Option<Instant>,
+    restore_at: Option<Instant>,
+    adaptive: Option<Adaptive>,
+}
+
+/// hill climbing on the throughput: step up while it pays off, step back when it doesn't
+#[derive(Debug)]
+struct Adaptive {
+    min: usize,
+    max: usize,
+    target: usize,
+    /// the count before the last step up, returned to when the step didn't pay off
+    previous: usize,
+    /// throughput before the last step up, `None` while the count holds
+    baseline: Option<f64>,
+    hold_until: Option<Instant>,
+    /// errors only lower the count once per sample
+    backed_off: bool,
+}
+
+/// one open connection, handed back when dropped
+pub(crate) struct ThrottlePermit {
+    throttle: Arc<Throttle>,
+}
+
+/// counts a caller of [`Throttle::acquire`] while it waits, also when it is cancelled
+struct Waiting<'a>(&'a Throttle);
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        self.0.state.lock().unwrap().waiting -= 1;
+    }
+}
+
+impl Throttle {
+    /// wait out a pause and for a free connection under the current limit
+    pub async fn acquire(self: &Arc<Self>) -> ThrottlePermit {
+        self.state.lock().unwrap().waiting += 1;
+        let _waiting = Waiting(self);
+        loop {
+            // registered before looking at the state, so a release in between isn't missed
+            let released = self.released.notified();
+            let wake = match self.state.lock().unwrap().try_acquire(Instant::now()) {
+                Ok(()) => {
+                    return ThrottlePermit {
+                        throttle: self.clone(),
+                    }
+                }
+                Err(wake) => wake,
+            };
+            match wake {
+                Some(wake) => {
+                    let _ = tokio::time::timeout_at(wake, released).await;
+                }
+                None => released.await,
+            }
+        }
+    }
+
+    /// the server asked to slow down, returns how long the connections pause
+    pub fn rate_limited(&self, retry_after: Option<Duration>) -> Duration {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        state.rate_limited(now, retry_after.unwrap_or(DEFAULT_PAUSE));
+        warn!(
+            limit = state.limit(),
+            ?retry_after,
+            "rate limited, pausing the connections"
+        );
+        state.remaining(now)
+    }
+
+    /// how much is left of the current pause
+    pub fn remaining(&self) -> Duration {
+        self.state.lock().unwrap().remaining(Instant::now())
+    }
+
+    /// let the measured throughput pick between `min` and `max` connections, starting at `min`
+    pub fn adapt(&self, min: usize, max: usize) {
+        let min = min.max(1);
+        self.state.lock().unwrap().adaptive = Some(Adaptive::new(min, max.max(min)));
+    }
+
+    /// the aggregate bytes per second since the last sample
+    pub fn sample(&self, throughput: f64) {
+        let mut state = self.state.lock().unwrap();
+        let Some(adaptive) = &mut state.adaptive else {
+            return;
+        };
+        let before = adaptive.target;
+        adaptive.sample(Instant::now(), throughput);
+        if adaptive.target != before {
+            info!(
+                connections = adaptive.target,
+                throughput = throughput as u64,
+                "connection count adjusted"
+            );
+            // waiters only look again on a release otherwise
+            self.released.notify_waiters();
+        }
+    }
+
+    /// a connection broke or misbehaved, the adaptive count takes a step back
+    pub fn failed(&self) {
+        if let Some(adaptive) = &mut self.state.lock().unwrap().adaptive {
+            adaptive.back_off(Instant::now(), false);
+        }
+    }
+}
+
+impl Drop for ThrottlePermit {
+    fn drop(&mut self) {
+        self.throttle.state.lock().unwrap().active -= 1;
+        self.throttle.released.notify_waiters();
+    }
+}
+
+impl State {
+    /// take a connection, or tell when it is worth looking again (`None`: once one is released)
+    fn try_acquire(&mut self, now: Instant) -> Result<(), Option<Instant>> {
+        self.demand = self.demand.max(self.active + self.waiting);
+        if let Some(paused_until) = self.paused_until.filter(|until| *until > now) {
+            return Err(Some(paused_until));
+        }
+        self.restore(now);
+        match self.limit() {
+            Some(limit) if self.active >= limit => Err(self.restore_at),
+            _ => {
+                self.active += 1;
+                Ok(())
+            }
+        }
+    }
+
+    /// how many connections may be open, `None` for no limit
+    fn limit(&self) -> Option<usize> {
+        match &self.adaptive {
+            Some(adaptive) => Some(adaptive.target),
+            None => self.limit,
+        }
+    }
+
+    fn rate_limited(&mut self, now: Instant, pause: Duration) {
+        let paused_until = now + pause;
+        match self.paused_until.filter(|until| *until > now) {
+            // the other connections of the same burst only make the pause longer
+            Some(until) => self.paused_until = Some(until.max(paused_until)),
+            None => {
+                self.paused_until = Some(paused_until);
+                if let Some(adaptive) = &mut self.adaptive {
+                    adaptive.back_off(now, true);
+                    return;
+                }
+                let current = self.limit.unwrap_or(self.demand.max(self.active));
+                self.limit = Some((current / 2).max(1));
+            }
+        }
+        if self.adaptive.is_none() {
+            self.restore_at = self.paused_until.map(|until| until + RESTORE_INTERVAL);
+        }
+    }
+
+    fn restore(&mut self, now: Instant) {
+        while let (Some(limit), Some(restore_at)) = (self.limit, self.restore_at) {
+            if restore_at > now {
+                return;
+            }
+            if limit * 2 >= self.demand {
+                self.limit = None;
+                self.restore_at = None;
+            } else {
+                self.limit = Some(limit * 2);
+                self.restore_at = Some(restore_at + RESTORE_INTERVAL);
+            }
+        }
+    }
+
+    fn remaining(&self, now: Instant) -> Duration {
+        self.paused_until
+            .map_or(Duration::ZERO, |until| until.saturating_duration_since(now))
+    }
+}
+
+impl Adaptive {
+    fn new(min: usize, max: usize) -> Adaptive {
+        Adaptive {
+            min,
+            max,
+            target: min,
+            previous: min,
+            // the first sample with any bytes is worth a step up
+            baseline: Some(0.0),
+            hold_until: None,
+            backed_off: false,
+        }
+    }
+
+    fn sample(&mut self, now: Instant, throughput: f64) {
+        self.backed_off = false;
+        match self.baseline {
+            Some(baseline) if throughput > baseline * (1.0 + MIN_GAIN) => {
+                if self.target < self.max {
+                    self.step_up(throughput);
+                } else {
+                    self.baseline = None;
+                }
+            }
+            Some(_) => {
+                // the last step didn't pay off
+                self.target = self.previous;
+                self.hold(now);
+            }
+            None if self.hold_until.is_some_and(|until| until <= now) && self.target < self.max => {
+                self.hold_until = None;
+                self.step_up(throughput);
+            }
+            None => (),
+        }
+    }
+
+    fn step_up(&mut self, throughput: f64) {
+        self.previous = self.target;
+        self.target = (self.target + (self.target / 2).max(1)).min(self.max);
+        self.baseline = Some(throughput);
+    }
+
+    /// halve on a rate limit, one less on an error, never below `min`
+    fn back_off(&mut self, now: Instant, rate_limited: bool) {
+        if self.backed_off && !rate_limited {
+            return;
+        }
+        self.backed_off = true;
+        let target = if rate_limited {
+            self.target / 2
+        } else {
+            self.target.saturating_sub(1)
+        };
+        self.target = target.max(self.min);
+        self.previous = self.target;
+        self.hold(now);
+    }
+
+    fn hold(&mut self, now: Instant) {
+        self.baseline = None;
+        self.hold_until = Some(now + HOLD);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use tokio::time::Instant;
+
+    use super::{Adaptive, State, HOLD, RESTORE_INTERVAL};
+
+    #[test]
+    fn test_throttle_state() {
+        let now = Instant::now();
+        let mut state = State {
+            waiting: 1,
+            ..Default::default()
+        };
+        for _ in 0..8 {
+            assert!(state.try_acquire(now).is_ok());
+        }
+        assert_eq!(state.demand, 8);
+
+        let pause = Duration::from_secs(2);
+        state.rate_limited(now, pause);
+        // the rest of the burst doesn't halve the limit again
+        state.rate_limited(now, Duration::from_secs(1));
+        assert_eq!(state.limit, Some(4));
+        assert_eq!(state.remaining(now), pause);
+        // the rate limited connections were dropped
+        state.active = 3;
+        assert_eq!(state.try_acquire(now), Err(Some(now + pause)));
+
+        let resumed = now + pause;
+        assert!(state.try_acquire(resumed).is_ok());
+        assert_eq!(
+            state.try_acquire(resumed),
+            Err(Some(resumed + RESTORE_INTERVAL))
+        );
+
+        // quiet long enough, the limit grows back and is lifted at the demand
+        state.restore(resumed + RESTORE_INTERVAL);
+        assert_eq!(state.limit, None);
+        assert!(state.try_acquire(resumed + RESTORE_INTERVAL).is_ok());
+    }
+
+    #[test]
+    fn test_adaptive() {
+        let now = Instant::now();
+        let mut adaptive = Adaptive::new(2, 8);
+        adaptive.sample(now, 100.0);
+        assert_eq!(adaptive.target, 3);
+        adaptive.sample(now, 150.0);
+        assert_eq!(adaptive.target, 4);
+        // 6 connections bring less than 10% more, back to 4 and hold
+        adaptive.sample(now, 190.0);
+        assert_eq!(adaptive.target, 6);
+        adaptive.sample(now, 200.0);
+        assert_eq!(adaptive.target, 4);
+        adaptive.sample(now, 200.0);
+        assert_eq!(adaptive.target, 4);
+        // after the hold it tries again
+        adaptive.sample(now + HOLD, 200.0);
+        assert_eq!(adaptive.target, 6);
+
+        // a burst of errors is one step, a rate limit halves, both stop at `min`
+        adaptive.back_off(now, false);
+        adaptive.back_off(now, false);
+        assert_eq!(adaptive.target, 5);
+        adaptive.back_off(now, true);
+        assert_eq!(adaptive.target, 2);
+
+        let mut state = State {
+            adaptive: Some(Adaptive::new(1, 4)),
+            ..Default::default()
+        };
+        assert!(state.try_acquire(now).is_ok());
+        assert_eq!(state.try_acquire(now), Err(None));
+    }
+}
@@ -0,0 +1,152 @@
+use std::future::Future;
+use std::time::Instant;
+
+use bytes::Bytes;
+use reqwest::Response;
+
+use crate::common;
+use crate::common::error::DownloadError;
+use crate::common::options::StallPolicy;
+
+/// Reads a response and gives up on it once it stalls, so the caller can reconnect from the
+/// bytes it has. One watchdog watches one response.
+pub(crate) struct Watchdog<'a> {
+    policy: Option<&'a StallPolicy>,
+    window_start: Instant,
+    window_bytes: u64,
+}
+
+impl<'a> Watchdog<'a> {
+    pub fn new(policy: Option<&'a StallPolicy>) -> Watchdog<'a> {
+        Watchdog {
+            policy,
+            window_start: Instant::now(),
+            window_bytes: 0,
+        }
+    }
+
+    /// wait for the response headers, they are held to the same timeout as the chunks
+    pub async fn response<F>(&self, request: F) -> common::error::Result<Response>
+    where
+        F: Future<Output = common::error::Result<Response>>,
+    {
+        let Some(policy) = self.policy else {
+            return request.await;
+        };
+        match tokio::time::timeout(policy.timeout, request).await {
+            Ok(resp) => resp,
+            Err(_) => Err(DownloadError::stalled(&format!(
+                "no response for {:?}",
+                policy.timeout
+            ))),
+        }
+    }
+
+    /// the next chunk of `resp`, a [`DownloadError::Stalled`] when no bytes arrive within the
+    /// timeout or a window ends below the throughput floor
+    pub async fn chunk(&mut self, resp: &mut Response) -> common::error::Result<Option<Bytes>> {
+        let Some(policy) = self.policy else {
+            return Ok(resp.chunk().await?);
+        };
+        let chunk = match tokio::time::timeout(policy.timeout, resp.chunk()).await {
+            Ok(chunk) => chunk?,
+            Err(_) => {
+                return Err(DownloadError::stalled(&format!(
+                    "no bytes for {:?}",
+                    policy.timeout
+                )))
+            }
+        };
+        if let Some(chunk) = &chunk {
+            self.check(policy, chunk.len() as u64)?;
+        }
+        Ok(chunk)
+    }
+
+    /// run `wait` without it counting against the response, e.g. while the writer is full.
+    /// the throughput window is moved on by the time it took
+    pub async fn paused<F: Future>(&mut self, wait: F) -> F::Output {
+        let started = Instant::now();
+        let output = wait.await;
+        self.window_start += started.elapsed();
+        output
+    }
+
+    fn check(&mut self, policy: &StallPolicy, bytes: u64) -> common::error::Result<()> {
+        let Some(min_throughput) = policy.min_throughput else {
+            return Ok(());
+        };
+        self.window_bytes += bytes;
+        let elapsed = self.window_start.elapsed();
+        if elapsed < policy.window {
+            return Ok(());
+        }
+        let throughput = self.window_bytes as f64 / elapsed.as_secs_f64();
+        if throughput < min_throughput as f64 {
+            return Err(DownloadError::stalled(&format!(
+                "{:.0} bytes/s over {:?}, the floor is {} bytes/s",
+                throughput, elapsed, min_throughput
+            )));
+        }
+        self.window_start = Instant::now();
+        self.window_bytes = 0;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::Watchdog;
+    use crate::common::error::DownloadError;
+    use crate::common::options::StallPolicy;
+
+    #[test]
+    fn test_throughput_floor() {
+        let policy = StallPolicy {
+            timeout: Duration::from_secs(30),
+            min_throughput: Some(1000),
+            window: Duration::from_millis(20),
+        };
+        let mut watchdog = Watchdog::new(Some(&policy));
+        assert!(watchdog.check(&policy, 10).is_ok());
+        std::thread::sleep(Duration::from_millis(25));
+        assert!(matches!(
+            watchdog.check(&policy, 10),
+            Err(DownloadError::Stalled(_))
+        ));
+
+        let mut watchdog = Watchdog::new(Some(&policy));
+        std::thread::sleep(Duration::from_millis(25));
+        assert!(watchdog.check(&policy, 1000).is_ok());
+        assert!(watchdog.check(&policy, 10).is_ok());
+    }
+
+    #[test]
+    fn test_paused_time_is_not_counted() {
+        let policy = StallPolicy {
+            timeout: Duration::from_secs(30),
+            min_throughput: Some(10_000),
+            window: Duration::from_millis(20),
+        };
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let mut watchdog = Watchdog::new(Some(&policy));
+        assert!(watchdog.check(&policy, 10).is_ok());
+        // blocked on the writer longer than the window, the window isn't over yet
+        rt.block_on(async {
+            watchdog
+                .paused(tokio::time::sleep(Duration::from_millis(25)))
+                .await
+        });
+        assert!(watchdog.check(&policy, 10).is_ok());
+        std::thread::sleep(Duration::from_millis(25));
+        assert!(matches!(
+            watchdog.check(&policy, 10),
+            Err(DownloadError::Stalled(_))
+        ));
+    }
+}
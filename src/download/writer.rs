@@ -0,0 +1,182 @@
+use bytes::Bytes;
+use futures::future::join_all;
+use tokio::runtime::Runtime;
+use tokio::sync::mpsc;
+use tokio::task::{JoinError, JoinHandle};
+
+use crate::common;
+use crate::common::error::DownloadError;
+
+use super::progress::Progress;
+use super::sink::StorageSink;
+
+/// chunks on their way to the disk, a slow disk holds the connections back beyond this
+const QUEUE_CHUNKS: usize = 64;
+
+enum Message {
+    Write {
+        sink: usize,
+        offset: u64,
+        bytes: Bytes,
+        thread_number: usize,
+    },
+    Finished {
+        thread_number: usize,
+    },
+}
+
+/// Writes what the segment tasks receive into their sinks on the calling thread, so the
+/// runtime's workers only ever wait on the network. Progress counts a chunk once it is written,
+/// the checkpoints never claim bytes a cache file doesn't hold yet.
+pub(crate) struct Writer {
+    sender: mpsc::Sender<Message>,
+    receiver: mpsc::Receiver<Message>,
+}
+
+/// the sending half for one segment, its offsets are remote bytes
+#[derive(Clone)]
+pub(crate) struct SegmentWriter {
+    sender: mpsc::Sender<Message>,
+    sink: usize,
+    /// the remote byte at offset 0 of the sink
+    sink_start: u64,
+    thread_number: usize,
+}
+
+impl Writer {
+    pub fn new() -> Writer {
+        let (sender, receiver) = mpsc::channel(QUEUE_CHUNKS);
+        Writer { sender, receiver }
+    }
+
+    /// segment `thread_number` writes into `sinks[sink]` of [`Writer::run`]
+    pub fn segment(&self, sink: usize, sink_start: u64, thread_number: usize) -> SegmentWriter {
+        SegmentWriter {
+            sender: self.sender.clone(),
+            sink,
+            sink_start,
+            thread_number,
+        }
+    }
+
+    /// write until every [`SegmentWriter`] is dropped. it blocks, so it has to run outside of
+    /// the runtime. on an error the segments fail with their next chunk
+    pub fn run(
+        self,
+        sinks: &mut [&mut dyn StorageSink],
+        progress: &Progress,
+    ) -> common::error::Result<()> {
+        let Writer {
+            sender,
+            mut receiver,
+        } = self;
+        drop(sender);
+        while let Some(message) = receiver.blocking_recv() {
+            match message {
+                Message::Write {
+                    sink,
+                    offset,
+                    bytes,
+                    thread_number,
+                } => {
+                    let sink = sinks.get_mut(sink).ok_or(DownloadError::system(&format!(
+                        "segment #{} writes into a sink that doesn't exist",
+                        thread_number
+                    )))?;
+                    sink.write_at(offset, &bytes)?;
+                    sink.flush()?;
+                    progress.inc(bytes.len(), thread_number);
+                }
+                Message::Finished { thread_number } => progress.finish(thread_number),
+            }
+        }
+        Ok(())
+    }
+
+    /// write for `tasks` until they are done, then join them. a failed write aborts the tasks
+    /// instead of letting them fetch bytes that can't be stored
+    pub fn drive<T>(
+        self,
+        rt: &Runtime,
+        tasks: Vec<JoinHandle<T>>,
+        sinks: &mut [&mut dyn StorageSink],
+        progress: &Progress,
+    ) -> (common::error::Result<()>, Vec<Result<T, JoinError>>) {
+        let written = self.run(sinks, progress);
+        if written.is_err() {
+            for task in &tasks {
+                task.abort();
+            }
+        }
+        (written, rt.block_on(join_all(tasks)))
+    }
+}
+
+impl SegmentWriter {
+    /// queue `bytes` received from remote byte `offset` on
+    pub async fn write(&self, offset: u64, bytes: Bytes) -> common::error::Result<()> {
+        self.send(Message::Write {
+            sink: self.sink,
+            offset: offset - self.sink_start,
+            bytes,
+            thread_number: self.thread_number,
+        })
+        .await
+    }
+
+    /// the segment is complete once everything queued before is written
+    pub async fn finish(&self) -> common::error::Result<()> {
+        self.send(Message::Finished {
+            thread_number: self.thread_number,
+        })
+        .await
+    }
+
+    async fn send(&self, message: Message) -> common::error::Result<()> {
+        self.sender
+            .send(message)
+            .await
+            .map_err(|_| DownloadError::system("the writer stopped"))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bytes::Bytes;
+
+    use super::Writer;
+    use crate::download::progress::Progress;
+    use crate::download::sink::{MemorySink, StorageSink};
+
+    #[test]
+    fn test_writer() {
+        // the writer blocks this thread, the segments run on a worker
+        let rt = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .enable_all()
+            .build()
+            .unwrap();
+        let mut progress = Progress::default();
+        progress.add(3, 1);
+        progress.add(2, 2);
+        let writer = Writer::new();
+        let first = writer.segment(0, 10, 1);
+        let second = writer.segment(1, 13, 2);
+        let task = rt.spawn(async move {
+            first.write(10, Bytes::from_static(b"ab")).await?;
+            second.write(13, Bytes::from_static(b"de")).await?;
+            first.write(12, Bytes::from_static(b"c")).await?;
+            first.finish().await
+        });
+        let (mut a, mut b) = (MemorySink::new(), MemorySink::new());
+        let mut sinks: [&mut dyn StorageSink; 2] = [&mut a, &mut b];
+        // runs until the task drops its segment writers
+        writer.run(&mut sinks, &progress).unwrap();
+        rt.block_on(task).unwrap().unwrap();
+        assert_eq!(a.as_slice(), b"abc");
+        assert_eq!(b.as_slice(), b"de");
+        let positions = progress.dump();
+        assert_eq!(positions[&1], (3, true));
+        assert_eq!(positions[&2], (2, false));
+    }
+}
@@ -0,0 +1,676 @@
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use flate2::read::DeflateDecoder;
+
+use super::finalize;
+use super::network::{self, Network};
+use super::report::{DownloadReport, TransferStats};
+use super::{free_file_name, Download};
+use crate::common;
+use crate::common::error::DownloadError;
+use crate::common::options::{DownloadOptions, ExistingFilePolicy};
+
+const END_OF_CENTRAL_DIRECTORY: u32 = 0x06054b50;
+const ZIP64_END_OF_CENTRAL_DIRECTORY: u32 = 0x06064b50;
+const ZIP64_LOCATOR: u32 = 0x07064b50;
+const CENTRAL_DIRECTORY_HEADER: u32 = 0x02014b50;
+const LOCAL_FILE_HEADER: u32 = 0x04034b50;
+const END_OF_CENTRAL_DIRECTORY_LEN: usize = 22;
+const ZIP64_LOCATOR_LEN: usize = 20;
+const ZIP64_END_OF_CENTRAL_DIRECTORY_LEN: usize = 56;
+const CENTRAL_DIRECTORY_HEADER_LEN: usize = 46;
+const LOCAL_FILE_HEADER_LEN: usize = 30;
+const MAX_COMMENT_LEN: usize = u16::MAX as usize;
+const ZIP64_EXTRA_FIELD: u16 = 0x0001;
+
+const METHOD_STORED: u16 = 0;
+const METHOD_DEFLATED: u16 = 8;
+
+/// A member of a remote zip archive, as listed in its central directory.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ZipEntry {
+    pub name: String,
+    /// 0 for stored, 8 for deflate, anything else can't be extracted
+    pub compression_method: u16,
+    pub compressed_size: u64,
+    pub size: u64,
+    pub crc32: u32,
+    pub encrypted: bool,
+    /// offset of the member's local header in the archive
+    pub header_offset: u64,
+}
+
+impl ZipEntry {
+    pub fn is_dir(&self) -> bool {
+        self.name.ends_with('/')
+    }
+}
+
+/// A zip archive read through range requests, only the central directory and the requested
+/// members are downloaded
+pub(crate) struct RemoteZip {
+    pub url: String,
+    pub threads: usize,
+    pub options: DownloadOptions,
+}
+
+impl RemoteZip {
+    pub fn entries(&self) -> common::error::Result<Vec<ZipEntry>> {
+        let length = self.content_length()?;
+        let tail_start =
+            length.saturating_sub((END_OF_CENTRAL_DIRECTORY_LEN + MAX_COMMENT_LEN) as u64);
+        let tail = self.read(tail_start..length)?;
+        let eocd = find_end_of_central_directory(&tail).ok_or(DownloadError::invalid_archive(
+            "end of central directory record not found, the file is not a zip archive",
+        ))?;
+        let mut directory = parse_end_of_central_directory(&tail[eocd..])?;
+        if directory.needs_zip64() {
+            let locator_start =
+                eocd.checked_sub(ZIP64_LOCATOR_LEN)
+                    .ok_or(DownloadError::invalid_archive(
+                        "zip64 end of central directory locator not found",
+                    ))?;
+            let record_offset = parse_zip64_locator(&tail[locator_start..eocd])?;
+            let record = self
+                .read(record_offset..record_offset + ZIP64_END_OF_CENTRAL_DIRECTORY_LEN as u64)?;
+            directory = parse_zip64_end_of_central_directory(&record)?;
+        }
+
+        let directory_range = directory.offset..directory.offset + directory.size;
+        if directory_range.end > length {
+            return Err(DownloadError::invalid_archive(
+                "central directory lies outside of the archive",
+            ));
+        }
+        let bytes = if directory_range.start >= tail_start {
+            // small archives: the tail already holds the whole directory
+            tail[(directory_range.start - tail_start) as usize
+                ..(directory_range.end - tail_start) as usize]
+                .to_vec()
+        } else {
+            self.read(directory_range)?
+        };
+        parse_central_directory(&bytes, directory.entries)
+    }
+
+    /// download and decompress the member called `name` into `output_file`
+    pub fn extract(
+        &self,
+        name: &str,
+        output_file: PathBuf,
+    ) -> common::error::Result<DownloadReport> {
+        let entry = self
+            .entries()?
+            .into_iter()
+            .find(|entry| entry.name == name)
+            .ok_or(DownloadError::parameter(&format!(
+                "{} is not a member of the archive",
+                name
+            )))?;
+        self.extract_entry(&entry, output_file)
+    }
+
+    /// the report's byte counts and segments are those of the member's data in the archive,
+    /// compressed or not
+    pub fn extract_entry(
+        &self,
+        entry: &ZipEntry,
+        mut output_file: PathBuf,
+    ) -> common::error::Result<DownloadReport> {
+        if entry.is_dir() {
+            return Err(DownloadError::parameter(&format!(
+                "{} is a directory",
+                entry.name
+            )));
+        }
+        if entry.encrypted {
+            return Err(DownloadError::parameter(&format!(
+                "{} is encrypted",
+                entry.name
+            )));
+        }
+        if entry.compression_method != METHOD_STORED && entry.compression_method != METHOD_DEFLATED
+        {
+            return Err(DownloadError::parameter(&format!(
+                "{} uses compression method {}, only stored and deflate are supported",
+                entry.name, entry.compression_method
+            )));
+        }
+
+        let header =
+            self.read(entry.header_offset..entry.header_offset + LOCAL_FILE_HEADER_LEN as u64)?;
+        let data_start = entry.header_offset + local_header_len(&header)?;
+        let data = data_start..data_start + entry.compressed_size;
+
+        if output_file.exists() {
+            match self.options.existing_file {
+                ExistingFilePolicy::Fail => {
+                    return Err(DownloadError::already_exists(&format!(
+                        "{} already exists",
+                        output_file.display()
+                    )))
+                }
+                ExistingFilePolicy::SkipIfMatch if matches_entry(&output_file, entry)? => {
+                    let stats = TransferStats::default();
+                    stats.set_adopted(entry.size);
+                    return Ok(stats.report(&self.url, output_file, entry.size));
+                }
+                ExistingFilePolicy::Rename => output_file = free_file_name(&output_file),
+                // the start of an inflated file says nothing about the compressed bytes
+                ExistingFilePolicy::AdoptPrefix if entry.compression_method != METHOD_STORED => {
+                    return Err(DownloadError::parameter(&format!(
+                        "{} is compressed, only a stored member can adopt the start of {}",
+                        entry.name,
+                        output_file.display()
+                    )))
+                }
+                _ => (),
+            }
+        }
+        if entry.compressed_size == 0 {
+            // ranges can't be empty, neither can deflate data, so this is an empty stored file
+            let part_path = finalize::part_path(&output_file)?;
+            File::create(&part_path)?.sync_all()?;
+            finalize::finalize(&part_path, &output_file, Some(entry.size), None)?;
+            return Ok(TransferStats::default().report(&self.url, output_file, 0));
+        }
+
+        if entry.compression_method == METHOD_STORED {
+            // the member is the byte range itself, it is downloaded straight to the target
+            let mut download = self.download(data);
+            download.filename = output_file.clone();
+            // the existing file was dealt with above, an adopted prefix is checked by the crc32
+            if download.options.existing_file != ExistingFilePolicy::AdoptPrefix {
+                download.options.existing_file = ExistingFilePolicy::Overwrite;
+            }
+            let report = download.get()?;
+            if let Err(e) = check_crc32(&mut File::open(&output_file)?, entry) {
+                let _ = fs::remove_file(&output_file);
+                return Err(e);
+            }
+            return Ok(report);
+        }
+
+        let compressed_path = compressed_path(&output_file)?;
+        let mut download = self.download(data);
+        download.filename = compressed_path.clone();
+        // only a temporary file of ours
+        download.options.existing_file = ExistingFilePolicy::Overwrite;
+        download.options.expected_sha256 = None;
+        let version_path = download.version_path()?;
+        let mut report = download.get()?;
+
+        let part_path = finalize::part_path(&output_file)?;
+        let result = inflate(&compressed_path, &part_path, entry);
+        fs::remove_file(&compressed_path)?;
+        // no version is recorded for an overwritten file, but one left behind by an older run
+        // would outlive the temporary file it belongs to
+        match fs::remove_file(&version_path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+        if let Err(e) = result {
+            let _ = fs::remove_file(&part_path);
+            return Err(e);
+        }
+        report.sha256 = finalize::finalize_with_sha256(
+            &part_path,
+            &output_file,
+            Some(entry.size),
+            self.options.expected_sha256.as_deref(),
+            None,
+        )?;
+        report.path = output_file;
+        report.total_bytes = entry.size;
+        Ok(report)
+    }
+
+    fn content_length(&self) -> common::error::Result<u64> {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        network::ranged_length(rt.block_on(Network::default().get_content_length(&self.url))?)
+    }
+
+    fn download(&self, range: Range<u64>) -> Download {
+        Download {
+            url: self.url.clone(),
+            threads: self.threads,
+            keep_cache: self.options.keep_cache,
+            options: self.options.clone(),
+            range: Some(range),
+            ..Default::default()
+        }
+    }
+
+    /// small reads of the archive structure, with the retries of any other range download
+    fn read(&self, range: Range<u64>) -> common::error::Result<Vec<u8>> {
+        let mut download = self.download(range);
+        download.threads = 1;
+        download.options.expected_sha256 = None;
+        download.get_bytes()
+    }
+}
+
+/// the compressed member is kept beside the target until it is inflated
+fn compressed_path(file_path: &Path) -> common::error::Result<PathBuf> {
+    let part_path = finalize::part_path(file_path)?;
+    Ok(part_path.with_extension("deflate"))
+}
+
+fn inflate(
+    compressed_path: &Path,
+    part_path: &Path,
+    entry: &ZipEntry,
+) -> common::error::Result<()> {
+    let mut decoder = DeflateDecoder::new(BufReader::new(File::open(compressed_path)?));
+    let mut writer = BufWriter::new(File::create(part_path)?);
+    let mut hasher = crc32fast::Hasher::new();
+    let mut buffer = vec![0u8; 64 * 1024];
+    let mut written = 0u64;
+    loop {
+        let count = decoder.read(&mut buffer).map_err(|e| {
+            DownloadError::invalid_archive(&format!("{} can't be inflated: {}", entry.name, e))
+        })?;
+        if count == 0 {
+            break;
+        }
+        hasher.update(&buffer[..count]);
+        writer.write_all(&buffer[..count])?;
+        written += count as u64;
+    }
+    writer
+        .into_inner()
+        .map_err(|e| e.into_error())?
+        .sync_all()?;
+    check_inflated(entry, written, hasher.finalize())
+}
+
+fn check_crc32<R: Read>(reader: &mut R, entry: &ZipEntry) -> common::error::Result<()> {
+    let mut hasher = crc32fast::Hasher::new();
+    let mut buffer = vec![0u8; 64 * 1024];
+    let mut length = 0u64;
+    loop {
+        let count = reader.read(&mut buffer)?;
+        if count == 0 {
+            break;
+        }
+        hasher.update(&buffer[..count]);
+        length += count as u64;
+    }
+    check_inflated(entry, length, hasher.finalize())
+}
+
+fn check_inflated(entry: &ZipEntry, length: u64, crc32: u32) -> common::error::Result<()> {
+    if length != entry.size {
+        return Err(DownloadError::size_mismatch(&format!(
+            "{} should be {} bytes, found {}",
+            entry.name, entry.size, length
+        )));
+    }
+    if crc32 != entry.crc32 {
+        return Err(DownloadError::checksum_mismatch(&format!(
+            "crc32 of {} should be {:08x}, found {:08x}",
+            entry.name, entry.crc32, crc32
+        )));
+    }
+    Ok(())
+}
+
+fn matches_entry(file_path: &Path, entry: &ZipEntry) -> common::error::Result<bool> {
+    if fs::metadata(file_path)?.len() != entry.size {
+        return Ok(false);
+    }
+    Ok(check_crc32(&mut File::open(file_path)?, entry).is_ok())
+}
+
+struct CentralDirectory {
+    entries: u64,
+    size: u64,
+    offset: u64,
+}
+
+impl CentralDirectory {
+    /// a saturated field means the real value is in the zip64 record
+    fn needs_zip64(&self) -> bool {
+        self.entries == u16::MAX as u64
+            || self.size == u32::MAX as u64
+            || self.offset == u32::MAX as u64
+    }
+}
+
+/// the last end of central directory record whose comment reaches the end of `tail`
+fn find_end_of_central_directory(tail: &[u8]) -> Option<usize> {
+    let last = tail.len().checked_sub(END_OF_CENTRAL_DIRECTORY_LEN)?;
+    (0..=last).rev().find(|start| {
+        let record = &tail[*start..];
+        u32_at(record, 0) == END_OF_CENTRAL_DIRECTORY
+            && END_OF_CENTRAL_DIRECTORY_LEN + u16_at(record, 20) as usize == record.len()
+    })
+}
+
+fn parse_end_of_central_directory(record: &[u8]) -> common::error::Result<CentralDirectory> {
+    if u16_at(record, 4) != 0 || u16_at(record, 6) != 0 {
+        return Err(DownloadError::invalid_archive(
+            "multi-disk archives are not supported",
+        ));
+    }
+    Ok(CentralDirectory {
+        entries: u16_at(record, 10) as u64,
+        size: u32_at(record, 12) as u64,
+        offset: u32_at(record, 16) as u64,
+    })
+}
+
+fn parse_zip64_locator(locator: &[u8]) -> common::error::Result<u64> {
+    if u32_at(locator, 0) != ZIP64_LOCATOR {
+        return Err(DownloadError::invalid_archive(
+            "zip64 end of central directory locator not found",
+        ));
+    }
+    Ok(u64_at(locator, 8))
+}
+
+fn parse_zip64_end_of_central_directory(record: &[u8]) -> common::error::Result<CentralDirectory> {
+    if record.len() < ZIP64_END_OF_CENTRAL_DIRECTORY_LEN
+        || u32_at(record, 0) != ZIP64_END_OF_CENTRAL_DIRECTORY
+    {
+        return Err(DownloadError::invalid_archive(
+            "zip64 end of central directory record not found",
+        ));
+    }
+    if u32_at(record, 16) != 0 || u32_at(record, 20) != 0 {
+        return Err(DownloadError::invalid_archive(
+            "multi-disk archives are not supported",
+        ));
+    }
+    Ok(CentralDirectory {
+        entries: u64_at(record, 32),
+        size: u64_at(record, 40),
+        offset: u64_at(record, 48),
+    })
+}
+
+fn parse_central_directory(bytes: &[u8], entries: u64) -> common::error::Result<Vec<ZipEntry>> {
+    let truncated = || DownloadError::invalid_archive("central directory is truncated");
+    let mut result = Vec::new();
+    let mut position = 0;
+    for _ in 0..entries {
+        let header = bytes.get(position..).ok_or_else(truncated)?;
+        if header.len() < CENTRAL_DIRECTORY_HEADER_LEN
+            || u32_at(header, 0) != CENTRAL_DIRECTORY_HEADER
+        {
+            return Err(truncated());
+        }
+        let name_len = u16_at(header, 28) as usize;
+        let extra_len = u16_at(header, 30) as usize;
+        let comment_len = u16_at(header, 32) as usize;
+        let name_end = CENTRAL_DIRECTORY_HEADER_LEN + name_len;
+        let extra_end = name_end + extra_len;
+        if header.len() < extra_end + comment_len {
+            return Err(truncated());
+        }
+
+        let mut compressed_size = u32_at(header, 20) as u64;
+        let mut size = u32_at(header, 24) as u64;
+        let mut header_offset = u32_at(header, 42) as u64;
+        if let Some(zip64) = find_extra_field(&header[name_end..extra_end], ZIP64_EXTRA_FIELD) {
+            // the zip64 field only holds the values that overflowed, in this order
+            let mut fields = zip64.chunks_exact(8).map(|field| u64_at(field, 0));
+            for value in [&mut size, &mut compressed_size, &mut header_offset] {
+                if *value == u32::MAX as u64 {
+                    *value = fields.next().ok_or(DownloadError::invalid_archive(
+                        "zip64 extra field is truncated",
+                    ))?;
+                }
+            }
+        }
+
+        result.push(ZipEntry {
+            name: String::from_utf8_lossy(&header[CENTRAL_DIRECTORY_HEADER_LEN..name_end])
+                .into_owned(),
+            compression_method: u16_at(header, 10),
+            compressed_size,
+            size,
+            crc32: u32_at(header, 16),
+            encrypted: u16_at(header, 8) & 1 != 0,
+            header_offset,
+        });
+        position += extra_end + comment_len;
+    }
+    Ok(result)
+}
+
+fn find_extra_field(mut extra: &[u8], id: u16) -> Option<&[u8]> {
+    while extra.len() >= 4 {
+        let len = u16_at(extra, 2) as usize;
+        let data = extra.get(4..4 + len)?;
+        if u16_at(extra, 0) == id {
+            return Some(data);
+        }
+        extra = &extra[4 + len..];
+    }
+    None
+}
+
+/// length of the local header including its name and extra field, these may differ from
+/// the central directory so the data offset is only known after reading it
+fn local_header_len(header: &[u8]) -> common::error::Result<u64> {
+    if header.len() < LOCAL_FILE_HEADER_LEN || u32_at(header, 0) != LOCAL_FILE_HEADER {
+        return Err(DownloadError::invalid_archive(
+            "local file header not found",
+        ));
+    }
+    Ok((LOCAL_FILE_HEADER_LEN + u16_at(header, 26) as usize + u16_at(header, 28) as usize) as u64)
+}
+
+fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(bytes[offset..offset + 2].try_into().unwrap())
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn u64_at(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Write;
+
+    use super::{
+        find_end_of_central_directory, local_header_len, parse_central_directory,
+        parse_end_of_central_directory, RemoteZip, ZipEntry, METHOD_DEFLATED, METHOD_STORED,
+    };
+    use crate::common::error::DownloadError;
+    use crate::common::options::{DownloadOptions, ExistingFilePolicy};
+    use crate::download::test_server::{Reply, TestServer};
+
+    /// an archive holding `content` as `name`
+    fn archive(name: &str, method: u16, content: &[u8]) -> Vec<u8> {
+        let data = if method == METHOD_DEFLATED {
+            let mut encoder =
+                flate2::write::DeflateEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(content).unwrap();
+            encoder.finish().unwrap()
+        } else {
+            content.to_vec()
+        };
+        let crc32 = crc32fast::hash(content);
+        let mut fields = method.to_le_bytes().to_vec();
+        fields.extend_from_slice(&[0; 4]);
+        fields.extend_from_slice(&crc32.to_le_bytes());
+        fields.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        fields.extend_from_slice(&(content.len() as u32).to_le_bytes());
+        fields.extend_from_slice(&(name.len() as u16).to_le_bytes());
+        fields.extend_from_slice(&[0, 0]);
+
+        let mut zip = 0x04034b50u32.to_le_bytes().to_vec();
+        zip.extend_from_slice(&[20, 0, 0, 0]);
+        zip.extend_from_slice(&fields);
+        zip.extend_from_slice(name.as_bytes());
+        zip.extend_from_slice(&data);
+        let directory_offset = zip.len();
+        zip.extend_from_slice(&0x02014b50u32.to_le_bytes());
+        zip.extend_from_slice(&[20, 0, 20, 0, 0, 0]);
+        zip.extend_from_slice(&fields);
+        // comment, disk, attributes and the local header at offset 0
+        zip.extend_from_slice(&[0; 14]);
+        zip.extend_from_slice(name.as_bytes());
+        let directory_len = zip.len() - directory_offset;
+        zip.extend_from_slice(&0x06054b50u32.to_le_bytes());
+        zip.extend_from_slice(&[0, 0, 0, 0, 1, 0, 1, 0]);
+        zip.extend_from_slice(&(directory_len as u32).to_le_bytes());
+        zip.extend_from_slice(&(directory_offset as u32).to_le_bytes());
+        zip.extend_from_slice(&[0, 0]);
+        zip
+    }
+
+    #[test]
+    fn test_extract_existing_file() {
+        let content = b"0123456789abcdefghij".repeat(10);
+        let stored = archive("data.txt", METHOD_STORED, &content);
+        let deflated = archive("data.txt", METHOD_DEFLATED, &content);
+        let server = TestServer::start(move |request| match request.path.as_str() {
+            "/stored.zip" => Reply::file(&stored, request),
+            _ => Reply::file(&deflated, request),
+        });
+        let temp = tempfile::tempdir().unwrap();
+        let target = temp.path().join("data.txt");
+        let extract = |path: &str, existing_file| {
+            RemoteZip {
+                url: server.url(path),
+                threads: 2,
+                options: DownloadOptions {
+                    existing_file,
+                    ..Default::default()
+                },
+            }
+            .extract("data.txt", target.clone())
+        };
+
+        std::fs::write(&target, b"mine").unwrap();
+        assert!(matches!(
+            extract("/stored.zip", ExistingFilePolicy::Fail),
+            Err(DownloadError::AlreadyExists(_))
+        ));
+        let report = extract("/deflated.zip", ExistingFilePolicy::Rename).unwrap();
+        assert_eq!(report.path, temp.path().join("data.1.txt"));
+        assert_eq!(std::fs::read(&report.path).unwrap(), content);
+        assert_eq!(std::fs::read(&target).unwrap(), b"mine");
+
+        // a prefix only carries over into a stored member
+        std::fs::write(&target, &content[..50]).unwrap();
+        assert!(matches!(
+            extract("/deflated.zip", ExistingFilePolicy::AdoptPrefix),
+            Err(DownloadError::ParameterError(_))
+        ));
+        extract("/stored.zip", ExistingFilePolicy::AdoptPrefix).unwrap();
+        assert_eq!(std::fs::read(&target).unwrap(), content);
+    }
+
+    #[test]
+    fn test_extract_deflated_leaves_only_entry() {
+        let content = b"0123456789abcdefghij".repeat(10);
+        let deflated = archive("data.txt", METHOD_DEFLATED, &content);
+        let server = TestServer::start(move |request| {
+            Reply::file(&deflated, request).header("ETag", "\"z1\"")
+        });
+        let temp = tempfile::tempdir().unwrap();
+        let target = temp.path().join("data.txt");
+        RemoteZip {
+            url: server.url("/deflated.zip"),
+            threads: 2,
+            options: DownloadOptions {
+                // the policy that records versions beside a download
+                existing_file: ExistingFilePolicy::SkipIfMatch,
+                ..Default::default()
+            },
+        }
+        .extract("data.txt", target.clone())
+        .unwrap();
+
+        assert_eq!(std::fs::read(&target).unwrap(), content);
+        let names: Vec<_> = std::fs::read_dir(temp.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(names, ["data.txt"]);
+    }
+
+    fn central_header(name: &str, sizes: u32, offset: u32, extra: &[u8]) -> Vec<u8> {
+        let mut header = 0x02014b50u32.to_le_bytes().to_vec();
+        header.extend_from_slice(&[20, 0, 20, 0, 0, 0, 8, 0, 0, 0, 0, 0]);
+        header.extend_from_slice(&0x352441c2u32.to_le_bytes());
+        header.extend_from_slice(&sizes.to_le_bytes());
+        header.extend_from_slice(&sizes.to_le_bytes());
+        header.extend_from_slice(&(name.len() as u16).to_le_bytes());
+        header.extend_from_slice(&(extra.len() as u16).to_le_bytes());
+        header.extend_from_slice(&[0; 6]);
+        header.extend_from_slice(&[0; 4]);
+        header.extend_from_slice(&offset.to_le_bytes());
+        header.extend_from_slice(name.as_bytes());
+        header.extend_from_slice(extra);
+        header
+    }
+
+    #[test]
+    fn test_parse_central_directory() {
+        let mut zip64 = vec![1, 0, 24, 0];
+        for value in [5_000_000_000u64, 4_000_000_000, 7_000_000_000] {
+            zip64.extend_from_slice(&value.to_le_bytes());
+        }
+        let mut directory = central_header("a.txt", 3, 0, &[]);
+        directory.extend(central_header("data/big.bin", u32::MAX, u32::MAX, &zip64));
+
+        let mut tail = directory.clone();
+        tail.extend_from_slice(&0x06054b50u32.to_le_bytes());
+        tail.extend_from_slice(&[0, 0, 0, 0, 2, 0, 2, 0]);
+        tail.extend_from_slice(&(directory.len() as u32).to_le_bytes());
+        tail.extend_from_slice(&100u32.to_le_bytes());
+        tail.extend_from_slice(&3u16.to_le_bytes());
+        tail.extend_from_slice(b"abc");
+
+        let eocd = find_end_of_central_directory(&tail).unwrap();
+        assert_eq!(eocd, directory.len());
+        let record = parse_end_of_central_directory(&tail[eocd..]).unwrap();
+        assert_eq!(
+            (record.entries, record.size, record.offset),
+            (2, directory.len() as u64, 100)
+        );
+        assert!(!record.needs_zip64());
+
+        let entries = parse_central_directory(&directory, 2).unwrap();
+        assert_eq!(
+            entries[0],
+            ZipEntry {
+                name: "a.txt".to_string(),
+                compression_method: 8,
+                compressed_size: 3,
+                size: 3,
+                crc32: 0x352441c2,
+                encrypted: false,
+                header_offset: 0,
+            }
+        );
+        assert_eq!(entries[1].size, 5_000_000_000);
+        assert_eq!(entries[1].compressed_size, 4_000_000_000);
+        assert_eq!(entries[1].header_offset, 7_000_000_000);
+        assert!(parse_central_directory(&directory[..50], 2).is_err());
+    }
+
+    #[test]
+    fn test_local_header_len() {
+        let mut header = 0x04034b50u32.to_le_bytes().to_vec();
+        header.extend_from_slice(&[0; 22]);
+        header.extend_from_slice(&5u16.to_le_bytes());
+        header.extend_from_slice(&9u16.to_le_bytes());
+        assert_eq!(local_header_len(&header).unwrap(), 44);
+        assert!(local_header_len(&header[1..]).is_err());
+    }
+}
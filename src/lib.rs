@@ -1,8 +1,18 @@
+use std::ops::Range;
 use std::path::PathBuf;
 
 pub mod common;
 mod download;
 
+pub use download::events::DownloadEvent;
+pub use download::network::RemoteInfo;
+pub use download::plan::{DownloadPlan, PlanStrategy, PlannedSegment};
+pub use download::remote_file::{AsyncRemoteFile, RemoteFile};
+pub use download::report::{DownloadReport, SegmentReport};
+pub use download::sink::{FileSink, MemorySink, OffsetSink, StorageSink};
+pub use download::stream::StreamReader;
+pub use download::zip::ZipEntry;
+
 /// download file paraA file download interface that supports resumable downloads and concurrency.
 ///
 /// # Examples
@@ -21,7 +31,7 @@ pub fn download<P: AsRef<str>>(
     url: P,
     thread: usize,
     output_file: PathBuf,
-) -> common::error::Result<()> {
+) -> common::error::Result<DownloadReport> {
     let download = download::Download {
         threads: thread,
         url: url.as_ref().to_owned(),
@@ -53,7 +63,7 @@ pub fn download_with_cleaner<P: AsRef<str>>(
     thread: usize,
     output_file: PathBuf,
     keep_cache: bool,
-) -> common::error::Result<()> {
+) -> common::error::Result<DownloadReport> {
     let download = download::Download {
         threads: thread,
         url: url.as_ref().to_owned(),
@@ -65,6 +75,345 @@ pub fn download_with_cleaner<P: AsRef<str>>(
     download.get()
 }
 
+/// download file with extra options, supports resumable downloads and concurrency.
+///
+/// # Examples
+///
+/// ```no_run
+/// use std::path::PathBuf;
+/// use pget::common::options::DownloadOptions;
+/// let p = PathBuf::from("test");
+/// let p = p.join("merges.txt");
+/// pget::download_with_options(
+/// "https://hf-mirror.com/Qwen/Qwen1.5-MoE-A2.7B/raw/main/merges.txt?token=abc".to_string(),
+/// num_cpus::get(),
+/// p.clone(),
+/// DownloadOptions {
+///     cache_key: Some("qwen-merges".to_string()),
+///     ..Default::default()
+/// },
+/// ).unwrap();
+/// ```
+pub fn download_with_options<P: AsRef<str>>(
+    url: P,
+    thread: usize,
+    output_file: PathBuf,
+    options: common::options::DownloadOptions,
+) -> common::error::Result<DownloadReport> {
+    let download = download::Download {
+        threads: thread,
+        url: url.as_ref().to_owned(),
+        filename: output_file,
+        keep_cache: options.keep_cache,
+        options,
+        ..Default::default()
+    };
+
+    download.get()
+}
+
+/// download the bytes `range` of a remote file into `output_file`, with the same segmentation,
+/// retry and resume as a full download.
+///
+/// # Examples
+///
+/// ```no_run
+/// use std::path::PathBuf;
+/// let p = PathBuf::from("test").join("model.header");
+/// pget::download_range(
+/// "https://hf-mirror.com/Qwen/Qwen1.5-0.5B/resolve/main/model.safetensors".to_string(),
+/// num_cpus::get(),
+/// 0..8,
+/// p.clone(),
+/// Default::default(),
+/// ).unwrap();
+/// ```
+pub fn download_range<P: AsRef<str>>(
+    url: P,
+    thread: usize,
+    range: Range<u64>,
+    output_file: PathBuf,
+    options: common::options::DownloadOptions,
+) -> common::error::Result<DownloadReport> {
+    let download = download::Download {
+        threads: thread,
+        url: url.as_ref().to_owned(),
+        filename: output_file,
+        keep_cache: options.keep_cache,
+        options,
+        range: Some(range),
+        ..Default::default()
+    };
+
+    download.get()
+}
+
+/// download the bytes `range` of a remote file into memory.
+///
+/// # Examples
+///
+/// ```no_run
+/// let header = pget::download_range_to_vec(
+/// "https://hf-mirror.com/Qwen/Qwen1.5-0.5B/resolve/main/model.safetensors".to_string(),
+/// 1,
+/// 0..8,
+/// Default::default(),
+/// ).unwrap();
+/// let header_len = u64::from_le_bytes(header.try_into().unwrap());
+/// ```
+pub fn download_range_to_vec<P: AsRef<str>>(
+    url: P,
+    thread: usize,
+    range: Range<u64>,
+    options: common::options::DownloadOptions,
+) -> common::error::Result<Vec<u8>> {
+    let download = download::Download {
+        threads: thread,
+        url: url.as_ref().to_owned(),
+        keep_cache: options.keep_cache,
+        options,
+        range: Some(range),
+        ..Default::default()
+    };
+
+    download.get_bytes()
+}
+
+/// ask the server about `url` with a one byte range request, without downloading the file.
+///
+/// # Examples
+///
+/// ```no_run
+/// let info = pget::probe(
+/// "https://hf-mirror.com/Qwen/Qwen1.5-0.5B/resolve/main/model.safetensors",
+/// ).unwrap();
+/// println!("{:?} bytes, ranges: {}, etag: {:?}", info.size, info.accepts_ranges, info.etag);
+/// ```
+pub fn probe<P: AsRef<str>>(url: P) -> common::error::Result<RemoteInfo> {
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    let network = download::network::Network::default();
+    rt.block_on(network.probe(&url.as_ref().to_owned()))
+}
+
+/// work out what [`download_with_options`] would do: segments, cached bytes, bytes remaining
+/// and disk space required. only the server is probed, nothing is transferred or written.
+///
+/// # Examples
+///
+/// ```no_run
+/// use std::path::PathBuf;
+/// let plan = pget::plan_download(
+/// "https://hf-mirror.com/Qwen/Qwen1.5-0.5B/resolve/main/model.safetensors",
+/// 4,
+/// PathBuf::from("test").join("model.safetensors"),
+/// Default::default(),
+/// ).unwrap();
+/// println!("{:?} of {:?} bytes left", plan.remaining_bytes, plan.total_bytes);
+/// ```
+pub fn plan_download<P: AsRef<str>>(
+    url: P,
+    thread: usize,
+    output_file: PathBuf,
+    options: common::options::DownloadOptions,
+) -> common::error::Result<DownloadPlan> {
+    let download = download::Download {
+        threads: thread,
+        url: url.as_ref().to_owned(),
+        filename: output_file,
+        keep_cache: options.keep_cache,
+        options,
+        ..Default::default()
+    };
+
+    download.plan()
+}
+
+/// work out what [`download_range`] would do, like [`plan_download`]
+pub fn plan_download_range<P: AsRef<str>>(
+    url: P,
+    thread: usize,
+    range: Range<u64>,
+    output_file: PathBuf,
+    options: common::options::DownloadOptions,
+) -> common::error::Result<DownloadPlan> {
+    let download = download::Download {
+        threads: thread,
+        url: url.as_ref().to_owned(),
+        filename: output_file,
+        keep_cache: options.keep_cache,
+        options,
+        range: Some(range),
+        ..Default::default()
+    };
+
+    download.plan()
+}
+
+/// download straight into `sink`, segments are written at their own offsets and nothing goes
+/// through the cache dir, so a failed download can't be resumed and `expected_sha256` is not
+/// checked. returns the number of bytes written.
+///
+/// # Examples
+///
+/// ```no_run
+/// let mut sink = pget::MemorySink::new();
+/// pget::download_to_sink(
+/// "https://hf-mirror.com/Qwen/Qwen1.5-MoE-A2.7B/raw/main/merges.txt",
+/// 4,
+/// &mut sink,
+/// Default::default(),
+/// ).unwrap();
+/// let merges = sink.into_inner();
+/// ```
+pub fn download_to_sink<P: AsRef<str>>(
+    url: P,
+    thread: usize,
+    sink: &mut dyn StorageSink,
+    options: common::options::DownloadOptions,
+) -> common::error::Result<u64> {
+    let download = download::Download {
+        threads: thread,
+        url: url.as_ref().to_owned(),
+        keep_cache: options.keep_cache,
+        options,
+        ..Default::default()
+    };
+
+    download.get_into(sink)
+}
+
+/// download into `writer` over up to `thread` parallel connections, the bytes are written
+/// strictly in order and nothing touches the disk. returns the number of bytes written.
+///
+/// # Examples
+///
+/// ```no_run
+/// # async fn run() {
+/// let mut file = tokio::fs::File::create("model.safetensors").await.unwrap();
+/// pget::download_to_writer(
+/// "https://hf-mirror.com/Qwen/Qwen1.5-0.5B/resolve/main/model.safetensors",
+/// 4,
+/// &mut file,
+/// Default::default(),
+/// ).await.unwrap();
+/// # }
+/// ```
+pub async fn download_to_writer<P, W>(
+    url: P,
+    thread: usize,
+    writer: &mut W,
+    options: common::options::StreamOptions,
+) -> common::error::Result<u64>
+where
+    P: AsRef<str>,
+    W: tokio::io::AsyncWrite + Unpin + ?Sized,
+{
+    let url = url.as_ref().to_owned();
+    let mut output = download::stream::Output::Writer(writer);
+    download::stream::stream(&url, thread, &options, &mut output).await
+}
+
+/// download to stdout, like [`download_to_writer`]. it starts its own runtime, so it can't be
+/// called from async code
+pub fn download_to_stdout<P: AsRef<str>>(
+    url: P,
+    thread: usize,
+    options: common::options::StreamOptions,
+) -> common::error::Result<u64> {
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    rt.block_on(download_to_writer(
+        url,
+        thread,
+        &mut tokio::io::stdout(),
+        options,
+    ))
+}
+
+/// a [`std::io::Read`] handle streaming the remote file in order, like [`download_to_writer`].
+///
+/// # Examples
+///
+/// ```no_run
+/// use std::io::Read;
+/// let mut reader = pget::download_reader(
+/// "https://hf-mirror.com/Qwen/Qwen1.5-MoE-A2.7B/raw/main/merges.txt",
+/// 4,
+/// Default::default(),
+/// ).unwrap();
+/// let mut text = String::new();
+/// reader.read_to_string(&mut text).unwrap();
+/// ```
+pub fn download_reader<P: AsRef<str>>(
+    url: P,
+    thread: usize,
+    options: common::options::StreamOptions,
+) -> common::error::Result<StreamReader> {
+    StreamReader::spawn(url.as_ref().to_owned(), thread, options)
+}
+
+/// list the members of a remote zip archive, only its central directory is downloaded.
+///
+/// # Examples
+///
+/// ```no_run
+/// let entries = pget::list_zip_entries(
+/// "https://example.com/datasets/images.zip",
+/// Default::default(),
+/// ).unwrap();
+/// for entry in entries {
+///     println!("{} {}", entry.name, entry.size);
+/// }
+/// ```
+pub fn list_zip_entries<P: AsRef<str>>(
+    url: P,
+    options: common::options::DownloadOptions,
+) -> common::error::Result<Vec<ZipEntry>> {
+    let zip = download::zip::RemoteZip {
+        url: url.as_ref().to_owned(),
+        threads: 1,
+        options,
+    };
+
+    zip.entries()
+}
+
+/// download and decompress a single member of a remote zip archive into `output_file`.
+/// stored and deflate members are supported, zip64 archives included. `options.existing_file`
+/// applies to `output_file`: `SkipIfMatch` compares the crc32, `AdoptPrefix` is only available
+/// for stored members.
+///
+/// # Examples
+///
+/// ```no_run
+/// use std::path::PathBuf;
+/// pget::extract_zip_entry(
+/// "https://example.com/datasets/images.zip",
+/// 4,
+/// "images/labels.csv",
+/// PathBuf::from("test").join("labels.csv"),
+/// Default::default(),
+/// ).unwrap();
+/// ```
+pub fn extract_zip_entry<P: AsRef<str>>(
+    url: P,
+    thread: usize,
+    name: &str,
+    output_file: PathBuf,
+    options: common::options::DownloadOptions,
+) -> common::error::Result<DownloadReport> {
+    let zip = download::zip::RemoteZip {
+        url: url.as_ref().to_owned(),
+        threads: thread,
+        options,
+    };
+
+    zip.extract(name, output_file)
+}
+
 #[cfg(not(feature = "progress_bar"))]
 use indicatif::ProgressBar;
 
@@ -100,7 +449,7 @@ pub fn download_with_custom_progress<P: AsRef<str>>(
     output_file: PathBuf,
     progress: Option<ProgressBar>,
     keep_cache: bool,
-) -> common::error::Result<()> {
+) -> common::error::Result<DownloadReport> {
     let download = download::Download {
         threads: thread,
         url: url.as_ref().to_owned(),
@@ -114,6 +463,7 @@ pub fn download_with_custom_progress<P: AsRef<str>>(
 }
 
 #[cfg(test)]
+#[allow(clippy::needless_return, clippy::unnecessary_to_owned)]
 mod test {
     use std::{fs::File, io::Read, path::PathBuf};
 
@@ -0,0 +1,132 @@
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+use pget::common::options::{AdaptiveConnections, DownloadOptions, StreamOptions};
+
+const USAGE: &str = "usage:
+  pget [-t <threads>|auto] [--report] <url> <output>      (`-` writes to stdout)
+  pget --dry-run [-t <threads>|auto] <url> <output>
+  pget zip list <url>
+  pget zip extract [-t <threads>|auto] [--report] <url> <member> [<output>]";
+
+fn main() -> ExitCode {
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    match run(args) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(message) => {
+            eprintln!("{}", message);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run(args: Vec<String>) -> Result<(), String> {
+    let (threads, adaptive_connections, args) = take_threads(args)?;
+    let options = DownloadOptions {
+        adaptive_connections,
+        ..Default::default()
+    };
+    let dry_run = args.iter().any(|arg| arg == "--dry-run");
+    let report = args.iter().any(|arg| arg == "--report");
+    let args = args
+        .iter()
+        .map(String::as_str)
+        .filter(|arg| *arg != "--dry-run" && *arg != "--report")
+        .collect::<Vec<_>>();
+    match args.as_slice() {
+        [url, output] if dry_run && *url != "zip" && *output != "-" => {
+            let plan = pget::plan_download(url, threads, PathBuf::from(output), options)
+                .map_err(|e| format!("{:?}", e))?;
+            let plan = serde_json::to_string_pretty(&plan).map_err(|e| e.to_string())?;
+            println!("{}", plan);
+            Ok(())
+        }
+        _ if dry_run => Err(USAGE.to_string()),
+        [_, "-"] if report => Err(USAGE.to_string()),
+        // the streaming download keeps a fixed number of connections
+        [_, "-"] if options.adaptive_connections.is_some() => {
+            Err("`-t auto` can't be used when writing to stdout".to_string())
+        }
+        ["zip", "list", url] => {
+            let entries = pget::list_zip_entries(url, DownloadOptions::default())
+                .map_err(|e| format!("{:?}", e))?;
+            for entry in entries {
+                println!(
+                    "{:>14} {:>14}  {}",
+                    entry.size, entry.compressed_size, entry.name
+                );
+            }
+            Ok(())
+        }
+        ["zip", "extract", url, member, rest @ ..] if rest.len() <= 1 => {
+            let output = match rest.first() {
+                Some(output) => PathBuf::from(output),
+                // the member's own name, without its dirs so it can't escape the current dir
+                None => PathBuf::from(member.rsplit('/').next().unwrap_or(member)),
+            };
+            let result = pget::extract_zip_entry(url, threads, member, output, options)
+                .map_err(|e| format!("{:?}", e))?;
+            print_report(report, &result)
+        }
+        [url, "-"] => {
+            let options = StreamOptions {
+                retries: options.retries,
+                stall: options.stall,
+                ..Default::default()
+            };
+            pget::download_to_stdout(url, threads, options)
+                .map(|_| ())
+                .map_err(|e| format!("{:?}", e))
+        }
+        [url, output] if *url != "zip" => {
+            let result = pget::download_with_options(url, threads, PathBuf::from(output), options)
+                .map_err(|e| format!("{:?}", e))?;
+            print_report(report, &result)
+        }
+        _ => Err(USAGE.to_string()),
+    }
+}
+
+/// with `--report`, print how the download went as JSON
+fn print_report(report: bool, result: &pget::DownloadReport) -> Result<(), String> {
+    if report {
+        let result = serde_json::to_string_pretty(result).map_err(|e| e.to_string())?;
+        println!("{}", result);
+    }
+    Ok(())
+}
+
+/// pull `-t <threads>` out of the arguments, the default is one connection per cpu.
+/// `-t auto` picks the connection count by the measured throughput
+fn take_threads(
+    args: Vec<String>,
+) -> Result<(usize, Option<AdaptiveConnections>, Vec<String>), String> {
+    let mut threads = None;
+    let mut adaptive = None;
+    let mut rest = Vec::with_capacity(args.len());
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        if arg == "-t" || arg == "--threads" {
+            let value = args.next().ok_or(USAGE.to_string())?;
+            if value == "auto" {
+                adaptive = Some(AdaptiveConnections::default());
+                continue;
+            }
+            threads = Some(
+                value
+                    .parse::<usize>()
+                    .ok()
+                    .filter(|threads| *threads > 0)
+                    .ok_or(format!("invalid thread count {}", value))?,
+            );
+        } else {
+            rest.push(arg);
+        }
+    }
+    let threads = threads.unwrap_or_else(|| {
+        std::thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(4)
+    });
+    Ok((threads, adaptive, rest))
+}